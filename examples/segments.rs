@@ -0,0 +1,45 @@
+//! Allocates and drops 2M boxed 32-byte values, then reports how many pages
+//! they took and how many segments were mapped for them.
+//!
+//! Count the system calls behind them by running it under strace:
+//!
+//! ```text
+//! cargo build --release --example segments
+//! strace -c -e trace=mmap,munmap,mprotect,madvise target/release/examples/segments
+//! ```
+//!
+//! A build from before segments, which mapped every page on its own, shows
+//! an `mmap`, an `mprotect` and an `munmap` per page under the same command.
+
+use std::fs;
+use std::time::Instant;
+
+use balloc::Balloc;
+
+#[global_allocator]
+static A: Balloc = Balloc::new();
+
+const COUNT: usize = 2_000_000;
+
+fn main() {
+    let maps_before = mappings();
+    let start = Instant::now();
+
+    let boxes = (0..COUNT).map(|i| Box::new([i; 4])).collect::<Vec<_>>();
+    let allocated = start.elapsed();
+
+    let stats = A.stats();
+    let pages = stats.size_classes.iter().map(|class| class.pages).sum::<usize>();
+    let maps_after = mappings();
+
+    drop(boxes);
+    let elapsed = start.elapsed();
+
+    println!("{} x 32 B: allocated in {:?}, dropped after {:?}", COUNT, allocated, elapsed);
+    println!("pages: {}, segments: {}", pages, stats.segments);
+    println!("/proc/self/maps entries: {} before, {} at the peak", maps_before, maps_after);
+}
+
+fn mappings() -> usize {
+    fs::read_to_string("/proc/self/maps").unwrap().lines().count()
+}
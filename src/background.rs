@@ -0,0 +1,85 @@
+//! Optional thread which takes maintenance off the threads allocating:
+//! purging decayed pages, handing emptied pages back to their segments and
+//! moving slots freed into released pages onto their free lists.
+//!
+//! Emptied pages are pushed onto a lock-free stack through their own memory,
+//! so retiring one never allocates or takes a lock. The thread itself never
+//! allocates once started, so it can't recurse into the allocator.
+
+use std::io;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::segment;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static RETIRED: AtomicPtr<Retired> = AtomicPtr::new(ptr::null_mut());
+
+/// Written to the start of a retired page.
+#[derive(Debug)]
+struct Retired {
+    next: *mut Retired,
+    size: usize,
+}
+
+/// Whether the thread runs, so it purges the decay instead of the threads
+/// allocating.
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// Starts the thread unless it already runs. It wakes up every `interval`
+/// and decays pages over `purge_decay`.
+pub fn start(interval: Duration, purge_decay: Duration) -> io::Result<()> {
+    if RUNNING.swap(true, Ordering::AcqRel) {
+        return Ok(())
+    }
+
+    let spawned = thread::Builder::new()
+        .name("balloc".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            free_retired();
+            crate::reclaim_orphans();
+            segment::purge_decayed(purge_decay);
+        });
+
+    if let Err(err) = spawned {
+        RUNNING.store(false, Ordering::Release);
+        return Err(err)
+    }
+    Ok(())
+}
+
+/// Leaves an emptied page of `size` bytes for the thread to free. Returns
+/// false if the thread doesn't run, so the caller must free it.
+pub fn retire(page: NonNull<u8>, size: usize) -> bool {
+    if !is_running() {
+        return false
+    }
+
+    let retired = page.as_ptr() as *mut Retired;
+    let mut next = RETIRED.load(Ordering::Relaxed);
+    loop {
+        unsafe { ptr::write(retired, Retired { next, size }) };
+        match RETIRED.compare_exchange_weak(next, retired, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return true,
+            Err(actual) => next = actual,
+        }
+    }
+}
+
+fn free_retired() {
+    // Taking the whole stack at once leaves no room for ABA.
+    let mut cursor = RETIRED.swap(ptr::null_mut(), Ordering::Acquire);
+
+    while let Some(page) = NonNull::new(cursor) {
+        unsafe {
+            let Retired { next, size } = ptr::read(page.as_ptr());
+            segment::free_page(page.cast(), size);
+            cursor = next;
+        }
+    }
+}
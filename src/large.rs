@@ -0,0 +1,90 @@
+//! Allocations too large for pages, each mapped directly from the OS behind
+//! a header, so they go back to the OS as soon as they're freed.
+
+use std::alloc::Layout;
+use std::mem;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::os;
+
+static MAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Stored at the start of every mapping, in front of the allocation.
+#[derive(Debug)]
+struct Header {
+    /// Length of the whole mapping, header included.
+    size: usize,
+}
+
+/// Whether `layout` can be mapped directly. Mappings are only aligned to the
+/// OS page size.
+pub fn fits(layout: &Layout) -> bool {
+    layout.align() <= os::page_size()
+}
+
+/// Maps a fresh allocation, which reads as zero.
+pub fn alloc(layout: &Layout) -> *mut u8 {
+    let offset = offset(layout.align());
+    let size = match mapped_size(layout.size(), offset) {
+        Some(size) => size,
+        None => return ptr::null_mut(),
+    };
+    let start = match os::map(size) {
+        Some(start) => start,
+        None => return ptr::null_mut(),
+    };
+
+    MAPPED_BYTES.fetch_add(size, Ordering::Relaxed);
+    unsafe {
+        ptr::write(start.as_ptr() as *mut Header, Header { size });
+        start.as_ptr().add(offset)
+    }
+}
+
+pub unsafe fn dealloc(ptr: *mut u8, layout: &Layout) {
+    let start = ptr.sub(offset(layout.align()));
+    let size = (*(start as *const Header)).size;
+    os::release(NonNull::new_unchecked(start), size);
+    MAPPED_BYTES.fetch_sub(size, Ordering::Relaxed);
+}
+
+/// Resizes the mapping behind `ptr`, letting the kernel move it without
+/// copying. Returns null and leaves the allocation untouched on failure.
+pub unsafe fn realloc(ptr: *mut u8, layout: &Layout, new_size: usize) -> *mut u8 {
+    let offset = offset(layout.align());
+    let start = ptr.sub(offset);
+    let size = (*(start as *const Header)).size;
+
+    let new_size = match mapped_size(new_size, offset) {
+        Some(new_size) => new_size,
+        None => return ptr::null_mut(),
+    };
+    if new_size == size {
+        return ptr
+    }
+
+    match os::remap(NonNull::new_unchecked(start), size, new_size) {
+        Some(start) => {
+            MAPPED_BYTES.fetch_add(new_size.wrapping_sub(size), Ordering::Relaxed);
+            ptr::write(start.as_ptr() as *mut Header, Header { size: new_size });
+            start.as_ptr().add(offset)
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+/// Bytes of all direct mappings, headers included.
+pub fn mapped_bytes() -> usize {
+    MAPPED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Distance from the start of the mapping to the allocation. It fits the
+/// header and keeps the allocation aligned.
+fn offset(align: usize) -> usize {
+    align.max(mem::size_of::<Header>())
+}
+
+fn mapped_size(size: usize, offset: usize) -> Option<usize> {
+    size.checked_add(offset)?.checked_next_multiple_of(os::page_size())
+}
@@ -1,56 +1,206 @@
 
 use std::alloc::{GlobalAlloc, Layout, System};
-use std::mem::{self, ManuallyDrop};
+use std::mem;
 use std::ptr::{self, NonNull};
 use std::cell::Cell;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use std::ops::Deref;
+use std::time::Duration;
 
-use memmap::MmapMut;
+mod background;
+mod large;
+mod os;
+mod segment;
+mod stats;
+
+pub use stats::{thread_stats, SizeClassStats, Stats, ThreadStats, TierStats};
 
-const USIZE_MAX: usize = !0;
 const U16_MAX: u16 = !0;
 
+const REMOTE_INDEX_MASK: u64 = U16_MAX as u64;
+const REMOTE_COUNT_SHIFT: u32 = 16;
+const REMOTE_FIELDS_MASK: u64 = (1 << 32) - 1;
+const REMOTE_ORPHAN: u64 = 1 << 32;
+const REMOTE_TAG: u64 = 1 << 33;
+const REMOTE_EMPTY: u64 = REMOTE_INDEX_MASK;
+
+/// Size of the allocator's own pages. It doesn't depend on the OS page size,
+/// as pages are carved out of segments which are aligned to their own size.
+/// Pages of larger size classes are several times this size.
 const PAGE_SIZE: usize = 4096;
-const PAGE_MASK: usize = !(PAGE_SIZE - 1);
 
 const HEAD_SIZE: usize = mem::size_of::<Head>();
 const HEAD_OWNED_SIZE: usize = mem::size_of::<HeadOwned>();
-const PAGE_BUF_SIZE: usize = PAGE_SIZE - HEAD_SIZE - HEAD_OWNED_SIZE;
+const HEADERS_SIZE: usize = HEAD_SIZE + HEAD_OWNED_SIZE;
 
-const HEAD_OFFSET: isize = (PAGE_SIZE - HEAD_SIZE) as isize;
-const HEAD_OWNED_OFFSET: isize = (PAGE_SIZE - HEAD_SIZE - HEAD_OWNED_SIZE) as isize;
+const _: () = assert!(mem::align_of::<usize>() == mem::align_of::<Head>());
+const _: () = assert!(mem::align_of::<usize>() == mem::align_of::<HeadOwned>());
 
 const UNIT_SIZE: usize = 8;
-const UNIT_PER_PAGE: usize = PAGE_BUF_SIZE / UNIT_SIZE;
 
-const MAX_SMALL_SLOT: usize = 64;
-const MAX_ALLOC_SIZE: usize = MAX_SMALL_SLOT * UNIT_SIZE;
+// Size classes up to `LINEAR_SIZE` are one unit apart. Beyond it every
+// doubling of the size is split into `CLASSES_PER_DOUBLING` classes, so a
+// slot wastes less than a unit for small requests and less than a quarter of
+// the request above `LINEAR_SIZE`. Pages hold at least `MIN_SLOTS_PER_PAGE`
+// slots, so the tail of a page too short for a slot is under a ninth of it.
+// The assertions below check both bounds.
+const LINEAR_SLOTS: usize = 8;
+const LINEAR_SIZE: usize = LINEAR_SLOTS * UNIT_SIZE;
+const CLASSES_PER_DOUBLING: usize = 4;
+
+const MAX_ALLOC_SIZE: usize = 32 << 10;
+const SIZE_CLASS_COUNT: usize =
+    LINEAR_SLOTS + CLASSES_PER_DOUBLING * (MAX_ALLOC_SIZE / LINEAR_SIZE).ilog2() as usize;
+
+/// Requests up to this size find their size class in `SIZE_CLASSES`.
+const MAX_LOOKUP_SIZE: usize = 1024;
+
+/// Fewest slots a page holds. Pages of larger size classes are grown until
+/// that many fit, which bounds the space lost to the page's tail.
+const MIN_SLOTS_PER_PAGE: usize = 8;
+
+/// Default time over which freed pages are purged.
+const PURGE_DECAY: Duration = Duration::from_secs(10);
+
+/// Default smallest request mapped directly from the OS.
+const LARGE_SIZE: usize = 1 << 20;
+
+/// Size class of every request size up to `MAX_LOOKUP_SIZE`, indexed by the
+/// size in units rounded up. Zero-size requests share the smallest class.
+static SIZE_CLASSES: [u8; MAX_LOOKUP_SIZE / UNIT_SIZE + 1] = size_classes();
+/// Slot size in bytes of every size class.
+static SLOT_SIZES: [u32; SIZE_CLASS_COUNT] = slot_sizes();
+/// Page size of every size class.
+static PAGE_SIZES: [usize; SIZE_CLASS_COUNT] = page_sizes();
+/// First size class whose pages span several `PAGE_SIZE`s. It and the
+/// classes after it serve medium requests.
+const FIRST_MEDIUM_CLASS: usize = first_medium_class();
+
+const _: () = {
+    let mut align = 1;
+    while align <= PAGE_SIZE {
+        let mut size = 0;
+        while size <= MAX_ALLOC_SIZE + 1 {
+            match get_size_class(size, align) {
+                Some(size_class) => {
+                    assert!(size_class < SIZE_CLASS_COUNT);
+                    let slot_size = SLOT_SIZES[size_class] as usize;
+                    assert!(slot_size >= size && slot_size.is_multiple_of(align));
+                    if align == 1 && size <= LINEAR_SIZE {
+                        assert!(size == 0 || slot_size - size < UNIT_SIZE);
+                    } else if align == 1 {
+                        assert!((slot_size - size) * CLASSES_PER_DOUBLING < size);
+                    }
+                }
+                None => assert!(size.next_multiple_of(align) > MAX_ALLOC_SIZE),
+            }
+            // Classes above 256 bytes are multiples of 64 bytes apart, so past
+            // that only the two sides of every 64 byte step differ.
+            size += if size < 256 || size % 64 == 0 { 1 } else { 63 };
+        }
+        align *= 2;
+    }
+
+    let mut size_class = 0;
+    while size_class < SIZE_CLASS_COUNT {
+        let buf_size = PAGE_SIZES[size_class] - HEADERS_SIZE;
+        let slot_size = SLOT_SIZES[size_class] as usize;
+        assert!(buf_size / slot_size <= U16_MAX as usize);
+        assert!(buf_size % slot_size * (MIN_SLOTS_PER_PAGE + 1) < buf_size);
+        size_class += 1;
+    }
+};
+
+pub struct Balloc<F: GlobalAlloc = System> {
+    fallback: F,
+    config: Config,
+}
 
-pub struct Balloc {
-    fallback: System,
+/// Tuning knobs of a `Balloc`, usable in a `static` initializer.
+///
+/// ```
+/// use balloc::{Balloc, Config};
+///
+/// #[global_allocator]
+/// static A: Balloc = Balloc::with_config(Config::new().max_small_size(256));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    max_small_size: usize,
+    segment_size: usize,
+    large_size: usize,
+    fallback: bool,
+    heap_size: usize,
+    huge_pages: HugePages,
+    purge: Purge,
+    purge_decay: Duration,
 }
 
-macro_rules! array_64 {
-    ($elem:expr) => ([
-        $elem, $elem, $elem, $elem, $elem, $elem, $elem, $elem,
-        $elem, $elem, $elem, $elem, $elem, $elem, $elem, $elem,
-        $elem, $elem, $elem, $elem, $elem, $elem, $elem, $elem,
-        $elem, $elem, $elem, $elem, $elem, $elem, $elem, $elem,
-        $elem, $elem, $elem, $elem, $elem, $elem, $elem, $elem,
-        $elem, $elem, $elem, $elem, $elem, $elem, $elem, $elem,
-        $elem, $elem, $elem, $elem, $elem, $elem, $elem, $elem,
-        $elem, $elem, $elem, $elem, $elem, $elem, $elem, $elem,
-    ]);
+/// Whether segments holding pages use huge pages to save TLB misses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePages {
+    Off,
+    /// Advise the kernel to back segments with transparent huge pages.
+    Transparent,
+    /// Map segments from the explicit `MAP_HUGETLB` pool, falling back to
+    /// transparent huge pages when it runs dry. Segments of a reserved heap
+    /// only use transparent huge pages.
+    Explicit,
+}
+
+/// How freed pages give their memory back to the OS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purge {
+    /// Keep the memory, so reusing the page costs nothing.
+    Off,
+    /// `MADV_DONTNEED`: the memory is dropped right away and faulted back in,
+    /// zeroed, on reuse.
+    DontNeed,
+    /// `MADV_FREE`: the OS drops the memory only under memory pressure, which
+    /// is cheaper but keeps it counted in RSS until then. Falls back to
+    /// `DontNeed` on kernels without it.
+    Free,
+}
+
+/// Where requests of a layout are served from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tier {
+    Page(usize),
+    /// Mapped directly from the OS.
+    Large,
+    Fallback,
 }
 
 thread_local! {
-    static LOCAL: [Cell<Option<Page>>; MAX_SMALL_SLOT] = array_64!(Cell::new(None));
+    static LOCAL: Local = const {
+        Local {
+            pages: [const { Cell::new(None) }; SIZE_CLASS_COUNT],
+            stats: stats::Shard::new(),
+        }
+    };
+}
+
+static ORPHANS: [Mutex<Orphans>; SIZE_CLASS_COUNT] = [const { Mutex::new(Orphans { first: None }) }; SIZE_CLASS_COUNT];
+
+struct Local {
+    pages: [Cell<Option<Page>>; SIZE_CLASS_COUNT],
+    stats: stats::Shard,
+}
+
+/// Released pages which still have free slots, linked through their
+/// `HeadOwned`. Released pages without free slots stay out of the list until
+/// the first free into them.
+#[derive(Debug)]
+struct Orphans {
+    first: Option<PageRef>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct PageRef {
     start: NonNull<u8>,
+    size: usize,
 }
 
 #[derive(Debug)]
@@ -60,16 +210,99 @@ struct Page {
 
 #[derive(Debug)]
 struct Head {
-    slot_size: u8,
-    is_owned: AtomicBool,
-    next_free: AtomicUsize,
+    size_class: u8,
+    slot_size: u32,
+    /// Stack of slots freed by other threads, packed as `tag|orphan|count|index`.
+    /// `index` is the top slot and `count` the number of slots on the stack.
+    /// Once the page is released and `orphan` is set, `count` holds the slots
+    /// still in use instead, so the free that drops it to zero owns the page.
+    /// The tag is bumped on every update, so a stale compare-exchange never
+    /// succeeds after the stack was taken.
+    next_free: AtomicU64,
+    /// Id of the thread allocating from the page, or which last did, so
+    /// `thread_stats` can tell frees by other threads apart. Zero if unknown.
+    owner: AtomicU32,
+    /// Remote frees by threads other than `owner` it didn't take back yet.
+    foreign_frees: AtomicU32,
 }
 
 #[derive(Debug)]
 struct HeadOwned {
     length: u16,
+    used: u16,
     next_free: u16,
-    handle: ManuallyDrop<MmapMut>,
+    /// Whether slots from `length` on were never written to and still read
+    /// as zero.
+    zeroed: bool,
+    prev_orphan: Option<PageRef>,
+    next_orphan: Option<PageRef>,
+}
+
+unsafe impl Send for Orphans {}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        for page in &self.pages {
+            if let Some(page) = page.take() {
+                page.release();
+            }
+        }
+        self.stats.retire();
+    }
+}
+
+impl Orphans {
+    fn push(&mut self, mut page: Page) {
+        let head = page.head_owned();
+        head.prev_orphan = None;
+        head.next_orphan = self.first;
+
+        if let Some(first) = self.first {
+            Page { page: first }.head_owned().prev_orphan = Some(*page);
+        }
+        self.first = Some(*page);
+    }
+
+    fn remove(&mut self, page: PageRef) -> Page {
+        let mut page = Page { page };
+        let head = page.head_owned();
+        let prev = head.prev_orphan.take();
+        let next = head.next_orphan.take();
+
+        match prev {
+            Some(prev) => Page { page: prev }.head_owned().next_orphan = next,
+            None => self.first = next,
+        }
+        if let Some(next) = next {
+            Page { page: next }.head_owned().prev_orphan = prev;
+        }
+        page
+    }
+
+    /// Takes the slots other threads freed into every listed page, so the
+    /// thread adopting one finds them on its free list.
+    fn reclaim(&mut self) {
+        let mut cursor = self.first;
+
+        while let Some(page) = cursor {
+            let mut page = Page { page };
+            page.reclaim();
+            cursor = page.head_owned().next_orphan;
+        }
+    }
+
+    fn adopt(&mut self) -> Option<Page> {
+        let mut cursor = self.first;
+
+        while let Some(page) = cursor {
+            let mut page = Page { page };
+            if page.claim() {
+                return Some(self.remove(*page))
+            }
+            cursor = page.head_owned().next_orphan;
+        }
+        None
+    }
 }
 
 impl Deref for Page {
@@ -81,113 +314,1173 @@ impl Deref for Page {
 }
 
 impl PageRef {
-    fn of(ptr: *mut u8) -> Self {
-        let start = ptr as usize & PAGE_MASK;
+    fn of(ptr: *mut u8, size_class: usize) -> Self {
+        let size = PAGE_SIZES[size_class];
+        let start = ptr as usize & !(size - 1);
         let start = unsafe { NonNull::new_unchecked(start as *mut u8) };
 
         PageRef {
             start,
+            size,
         }
     }
 
     fn head(&self) -> &Head {
         unsafe {
-            let head = self.start.as_ptr().offset(HEAD_OFFSET) as *const Head;
+            let head = self.start.as_ptr().add(self.size - HEAD_SIZE) as *const Head;
             &*head
         }
     }
 
     fn max_len(&self) -> u16 {
-        (PAGE_BUF_SIZE / self.head().slot_size as usize) as u16
+        ((self.size - HEADERS_SIZE) / self.head().slot_size as usize) as u16
+    }
+
+    fn slot(&self, index: u16) -> *mut u8 {
+        let offset = index as usize * self.head().slot_size as usize;
+        unsafe { self.start.as_ptr().add(offset) }
+    }
+
+    fn index_of(&self, slot: *mut u8) -> u16 {
+        let offset = slot as usize - self.start.as_ptr() as usize;
+        (offset / self.head().slot_size as usize) as u16
+    }
+
+    fn free_remote(&self, slot: *mut u8) {
+        let index = self.index_of(slot);
+        let max_len = self.max_len();
+        let next_free = &self.head().next_free;
+        let mut orphans = None;
+        let mut current = next_free.load(Ordering::Relaxed);
+
+        let next = loop {
+            let count = remote_count(current);
+            let next = if current & REMOTE_ORPHAN == 0 {
+                remote_next(current, count + 1, index)
+            } else if count == max_len && orphans.is_none() {
+                orphans = Some(lock(&ORPHANS[self.head().size_class as usize]));
+                current = next_free.load(Ordering::Relaxed);
+                continue
+            } else {
+                remote_next(current, count - 1, index)
+            };
+
+            unsafe { ptr::write(slot as *mut u16, current as u16) };
+            match next_free.compare_exchange_weak(current, next, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break next,
+                Err(actual) => current = actual,
+            }
+        };
+
+        let was_full = remote_count(current) == max_len;
+        let live = remote_count(next);
+        if next & REMOTE_ORPHAN == 0 || (!was_full && live != 0) {
+            return
+        }
+
+        let mut orphans = orphans.unwrap_or_else(|| lock(&ORPHANS[self.head().size_class as usize]));
+        if live != 0 {
+            orphans.push(Page { page: *self });
+            return
+        }
+
+        let page = if was_full { Page { page: *self } } else { orphans.remove(*self) };
+        drop(orphans);
+        page.unmap();
+    }
+
+    fn take_remote(&self) -> (u16, u16) {
+        let next_free = &self.head().next_free;
+        let mut current = next_free.load(Ordering::Relaxed);
+
+        loop {
+            let index = current as u16;
+            if index == U16_MAX { return (U16_MAX, 0) }
+            let next = remote_next(current, 0, U16_MAX);
+
+            match next_free.compare_exchange_weak(current, next, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return (index, remote_count(current)),
+                Err(actual) => current = actual,
+            }
+        }
     }
 }
 
 impl Page {
-    fn new(slot_size: u8) -> Self {
-        assert_eq!(mem::align_of::<usize>(), mem::align_of::<Head>());
-        assert_eq!(mem::align_of::<usize>(), mem::align_of::<HeadOwned>());
-
-        let mut handle = MmapMut::map_anon(PAGE_SIZE).expect("OOM");
-        let start = handle.as_mut_ptr();
+    fn new(size_class: u8, config: &Config, owner: u32) -> Option<Self> {
+        let size = PAGE_SIZES[size_class as usize];
+        let (start, zeroed) = segment::alloc_page(size, config)?;
+        let page = PageRef { start, size };
 
         let head = Head {
-            slot_size,
-            is_owned: AtomicBool::new(true),
-            next_free: AtomicUsize::new(USIZE_MAX),
+            size_class,
+            slot_size: SLOT_SIZES[size_class as usize],
+            next_free: AtomicU64::new(REMOTE_EMPTY),
+            owner: AtomicU32::new(owner),
+            foreign_frees: AtomicU32::new(0),
         };
         let head_owned = HeadOwned {
             length: 0,
+            used: 0,
             next_free: U16_MAX,
-            handle: ManuallyDrop::new(handle),
+            zeroed,
+            prev_orphan: None,
+            next_orphan: None,
         };
 
         unsafe {
-            ptr::write(start.offset(HEAD_OFFSET) as *mut Head, head);
-            ptr::write(start.offset(HEAD_OWNED_OFFSET) as *mut HeadOwned, head_owned);
+            ptr::write(start.as_ptr().add(size - HEAD_SIZE) as *mut Head, head);
+            ptr::write(start.as_ptr().add(size - HEADERS_SIZE) as *mut HeadOwned, head_owned);
+        }
+
+        stats::record_page(size_class as usize, true);
+        Some(Page { page })
+    }
+
+    fn acquire(size_class: u8, config: &Config, stats: Option<&stats::Shard>) -> Option<Self> {
+        let owner = stats.map_or(0, stats::Shard::thread_id);
+        if let Some(page) = Page::adopt(size_class) {
+            if page.own(owner) {
+                stats::count(stats, |counts| counts.pages_adopted += 1);
+            }
+            return Some(page)
+        }
+
+        let page = Page::new(size_class, config, owner)?;
+        stats::count(stats, |counts| counts.pages_created += 1);
+        Some(page)
+    }
+
+    fn adopt(size_class: u8) -> Option<Self> {
+        lock(&ORPHANS[size_class as usize]).adopt()
+    }
+
+    /// Makes `owner` the thread allocating from an adopted page, telling
+    /// whether another thread owned it before. Frees by other threads the
+    /// previous owner didn't take back aren't counted for the new one.
+    fn own(&self, owner: u32) -> bool {
+        let head = self.head();
+        if head.owner.swap(owner, Ordering::Relaxed) == owner {
+            return false
+        }
+        head.foreign_frees.store(0, Ordering::Relaxed);
+        true
+    }
+
+    fn claim(&mut self) -> bool {
+        let used = self.head_owned().used;
+        let next_free = &self.head().next_free;
+        let mut current = next_free.load(Ordering::Relaxed);
+
+        loop {
+            let live = remote_count(current);
+            if live == 0 { return false }
+            let next = remote_next(current, used - live, current as u16) & !REMOTE_ORPHAN;
+
+            match next_free.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
         }
+    }
+
+    /// Moves the slots freed by other threads into a released page onto its
+    /// own free list, leaving the count of slots in use alone. Its orphans
+    /// must be locked, which keeps the page from being adopted meanwhile.
+    fn reclaim(&mut self) {
+        let next_free = &self.head().next_free;
+        let mut current = next_free.load(Ordering::Relaxed);
+
+        let mut index = loop {
+            let live = remote_count(current);
+            // Without slots in use the page is about to be unmapped.
+            if current as u16 == U16_MAX || live == 0 { return }
+            let next = remote_next(current, live, U16_MAX);
 
-        let page = PageRef { start: unsafe { NonNull::new_unchecked(start) } };
-        Page { page }
+            match next_free.compare_exchange_weak(current, next, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => break current as u16,
+                Err(actual) => current = actual,
+            }
+        };
+
+        // No thread takes them back, so none counts them as received.
+        self.head().foreign_frees.store(0, Ordering::Relaxed);
+        while index != U16_MAX {
+            let slot = self.slot(index);
+            let next = unsafe { ptr::read(slot as *const u16) };
+            let head = self.head_owned();
+            unsafe { ptr::write(slot as *mut u16, head.next_free) };
+            head.next_free = index;
+            head.used -= 1;
+            index = next;
+        }
     }
 
     fn head_owned(&mut self) -> &mut HeadOwned {
         unsafe {
-            let head = self.page.start.as_ptr().offset(HEAD_OWNED_OFFSET) as *mut HeadOwned;
+            let head = self.start.as_ptr().add(self.size - HEADERS_SIZE) as *mut HeadOwned;
             &mut *head
         }
     }
 
-    fn release(self) {
-        self.head().is_owned.store(false, Ordering::Release);
+    /// Also tells whether the slot still reads as zero.
+    fn alloc(&mut self, stats: Option<&stats::Shard>) -> Option<(NonNull<u8>, bool)> {
+        let max_len = self.max_len();
+        let mut next_free = self.head_owned().next_free;
+
+        if next_free == U16_MAX {
+            let (index, count) = self.take_remote();
+            self.head_owned().used -= count;
+            if count != 0 {
+                let foreign = self.head().foreign_frees.swap(0, Ordering::Relaxed);
+                stats::count(stats, |counts| counts.remote_frees_received += foreign as usize);
+            }
+            next_free = index;
+        }
+
+        let (slot, zeroed) = if next_free != U16_MAX {
+            let slot = self.slot(next_free);
+            self.head_owned().next_free = unsafe { ptr::read(slot as *const u16) };
+            (slot, false)
+        } else {
+            let length = self.head_owned().length;
+            if length == max_len { return None }
+            self.head_owned().length = length + 1;
+            (self.slot(length), self.head_owned().zeroed)
+        };
+
+        self.head_owned().used += 1;
+        Some((NonNull::new(slot)?, zeroed))
+    }
+
+    fn free(&mut self, slot: *mut u8) {
+        let index = self.index_of(slot);
+        let head = self.head_owned();
+        unsafe { ptr::write(slot as *mut u16, head.next_free) };
+        head.next_free = index;
+        head.used -= 1;
     }
 
-    fn unmap(mut self) {
-        unsafe {
-            let _: MmapMut = ptr::read_volatile(&mut *self.head_owned().handle);
+    fn release(mut self) {
+        let max_len = self.max_len();
+        let used = self.head_owned().used;
+        let mut orphans = lock(&ORPHANS[self.head().size_class as usize]);
+        let next_free = &self.head().next_free;
+        let mut current = next_free.load(Ordering::Relaxed);
+
+        let live = loop {
+            let count = remote_count(current);
+            if count == used {
+                drop(orphans);
+                return self.unmap()
+            }
+            let next = remote_next(current, used - count, current as u16) | REMOTE_ORPHAN;
+
+            match next_free.compare_exchange_weak(current, next, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break used - count,
+                Err(actual) => current = actual,
+            }
+        };
+
+        if live < max_len {
+            orphans.push(self);
+        }
+    }
+
+    fn unmap(self) {
+        stats::record_page(self.head().size_class as usize, false);
+        if !background::retire(self.start, self.size) {
+            unsafe { segment::free_page(self.start, self.size) };
         }
     }
 }
 
-unsafe impl GlobalAlloc for Balloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if layout.size() > MAX_ALLOC_SIZE {
-            return self.fallback.alloc(layout)
+impl Config {
+    pub const fn new() -> Self {
+        Config {
+            max_small_size: MAX_ALLOC_SIZE,
+            segment_size: segment::SEGMENT_SIZE,
+            large_size: LARGE_SIZE,
+            fallback: true,
+            heap_size: 0,
+            huge_pages: HugePages::Off,
+            purge: Purge::DontNeed,
+            purge_decay: PURGE_DECAY,
         }
+    }
+
+    /// Largest request served from pages. Larger requests go to the fallback
+    /// unless they are mapped directly. Values above the largest size class
+    /// are clamped to it.
+    pub const fn max_small_size(mut self, size: usize) -> Self {
+        self.max_small_size = if size < MAX_ALLOC_SIZE { size } else { MAX_ALLOC_SIZE };
+        self
+    }
+
+    /// Size of the segments pages are carved from, 4 MiB by default. Larger
+    /// segments take fewer mappings, but an empty one kept for reuse holds
+    /// more memory. Rounded up to a power of two between 2 MiB, which fits
+    /// four pages of the largest size class, and 32 MiB. Segments are shared
+    /// by the whole process, so the first allocator to map one fixes it.
+    pub const fn segment_size(mut self, size: usize) -> Self {
+        self.segment_size = if size < segment::MIN_SEGMENT_SIZE {
+            segment::MIN_SEGMENT_SIZE
+        } else if size > segment::MAX_SEGMENT_SIZE {
+            segment::MAX_SEGMENT_SIZE
+        } else {
+            size.next_power_of_two()
+        };
+        self
+    }
+
+    /// Smallest request, 1 MiB by default, mapped directly from the OS.
+    /// Such requests are returned to the OS as soon as they're freed and
+    /// grow in place or by remapping instead of copying. Requests between
+    /// `max_small_size` and this go to the fallback, as do requests aligned
+    /// beyond the OS page size. Pass `usize::MAX` to never map directly.
+    pub const fn large_size(mut self, size: usize) -> Self {
+        self.large_size = size;
+        self
+    }
+
+    /// Whether requests neither pages nor direct mappings serve go to the
+    /// fallback allocator.
+    /// If disabled they fail with a null pointer instead.
+    pub const fn fallback(mut self, enabled: bool) -> Self {
+        self.fallback = enabled;
+        self
+    }
+
+    /// Reserves `size` bytes of address space, such as 64 GiB, on the first
+    /// allocation and takes every page from it, so `Balloc::owns` can tell
+    /// them apart with a range check. Pages fail to allocate once it's used up.
+    /// The reservation is shared by the whole process and made only once.
+    pub const fn reserve_heap(mut self, size: usize) -> Self {
+        self.heap_size = size;
+        self
+    }
+
+    /// Backs segments with huge pages. Segments are aligned to their size of
+    /// at least 2 MiB, so every huge page lies within one of them. Freed pages of such
+    /// segments are only purged once a whole 2 MiB huge page is free, and
+    /// always through the decay, even a zero one, so they wait for the next
+    /// new page or `Balloc::purge_now`.
+    pub const fn huge_pages(mut self, mode: HugePages) -> Self {
+        self.huge_pages = mode;
+        self
+    }
+
+    /// How the memory of pages is returned to the OS once all their slots
+    /// are freed. Their address range stays mapped for cheap reuse.
+    pub const fn purge(mut self, mode: Purge) -> Self {
+        self.purge = mode;
+        self
+    }
+
+    /// Time over which freed pages are purged, 10 seconds by default. Pages
+    /// are kept unpurged in proportion to how much of it is left for them,
+    /// so bursts reuse them without faulting, while idle memory still goes
+    /// back. The decay advances whenever a thread takes a new page.
+    /// Zero purges pages as soon as they're freed.
+    pub const fn purge_decay(mut self, window: Duration) -> Self {
+        self.purge_decay = window;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new()
+    }
+}
+
+impl Balloc {
+    pub const fn new() -> Self {
+        Balloc::with_config(Config::new())
+    }
+
+    pub const fn with_config(config: Config) -> Self {
+        Balloc::with_fallback(System, config)
+    }
+}
+
+impl<F: GlobalAlloc> Balloc<F> {
+    /// Creates an allocator which sends requests pages can't serve to `fallback`.
+    pub const fn with_fallback(fallback: F, config: Config) -> Self {
+        Balloc {
+            fallback,
+            config,
+        }
+    }
+
+    pub const fn fallback(&self) -> &F {
+        &self.fallback
+    }
+
+    pub const fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Whether `ptr` points into the heap reserved through
+    /// `Config::reserve_heap`. Always false if no heap was reserved.
+    pub fn owns(&self, ptr: *const u8) -> bool {
+        segment::heap_contains(ptr)
+    }
+
+    /// Collects statistics of every `Balloc` in the process.
+    pub fn stats(&self) -> Stats {
+        stats::collect()
+    }
+
+    /// Purges every freed page the decay still keeps, regardless of how
+    /// recently it was freed.
+    pub fn purge_now(&self) {
+        segment::purge_all();
+    }
+
+    /// Starts a thread which wakes up every `interval` to purge decayed pages,
+    /// free pages emptied by other threads and reclaim the slots freed into
+    /// released pages, so the threads allocating don't pay for it. Emptied
+    /// pages wait for the thread before they can be reused, so a short
+    /// interval keeps them from piling up. Only one such thread runs per
+    /// process, using the decay window of the allocator which started it.
+    /// Later calls do nothing. Fails with `InvalidInput` if `interval` is zero.
+    pub fn start_background_thread(&self, interval: Duration) -> io::Result<()> {
+        if interval.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero background thread interval"))
+        }
+        background::start(interval, self.config.purge_decay)
+    }
+
+    /// Bytes of memory backed by explicit huge pages from `MAP_HUGETLB`.
+    pub fn huge_page_bytes(&self) -> usize {
+        segment::huge_page_bytes()
+    }
+
+    /// Bytes of memory advised to use transparent huge pages. The kernel
+    /// decides how much of it they actually back, which shows as
+    /// `AnonHugePages` in `/proc/self/smaps`.
+    pub fn advised_huge_page_bytes(&self) -> usize {
+        segment::advised_huge_page_bytes()
+    }
+
+    fn tier(&self, layout: &Layout) -> Tier {
+        if layout.size() <= self.config.max_small_size {
+            if let Some(size_class) = get_size_class(layout.size(), layout.align()) {
+                return Tier::Page(size_class)
+            }
+        }
+        if layout.size() >= self.config.large_size && large::fits(layout) {
+            return Tier::Large
+        }
+        Tier::Fallback
+    }
+
+    /// Takes a slot of `size_class` for `size` bytes, telling whether it
+    /// still reads as zero.
+    fn alloc_slot(&self, size_class: usize, size: usize) -> Option<(NonNull<u8>, bool)> {
+        if self.config.heap_size != 0 {
+            segment::reserve_heap(&self.config);
+        }
+
+        LOCAL.try_with(|local| {
+            let page = local.pages[size_class].take();
+            let (slot, page) = alloc_small(page, size_class, &self.config, Some(&local.stats))?;
+            local.pages[size_class].set(Some(page));
+            local.stats.record(Tier::Page(size_class), size, true);
+            Some(slot)
+        }).unwrap_or_else(|_| {
+            let (slot, page) = alloc_small(None, size_class, &self.config, None)?;
+            page.release();
+            stats::record(Tier::Page(size_class), size, true);
+            Some(slot)
+        })
+    }
+}
+
+impl<F: GlobalAlloc + Default> Default for Balloc<F> {
+    fn default() -> Self {
+        Balloc::with_fallback(F::default(), Config::new())
+    }
+}
 
-        let slot_size = get_slot_size(layout.size());
-        let page = LOCAL.with(|local| local[slot_size].take())
-            .unwrap_or_else(|| Page::new(slot_size as u8));
+unsafe impl<F: GlobalAlloc> GlobalAlloc for Balloc<F> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let tier = self.tier(&layout);
+        let result = match tier {
+            Tier::Page(size_class) => {
+                return self.alloc_slot(size_class, layout.size()).map_or(ptr::null_mut(), |(slot, _)| slot.as_ptr())
+            }
+            Tier::Large => large::alloc(&layout),
+            Tier::Fallback if self.config.fallback => self.fallback.alloc(layout),
+            Tier::Fallback => ptr::null_mut(),
+        };
 
-        ptr::null_mut()
+        if !result.is_null() {
+            stats::record(tier, layout.size(), true);
+        }
+        result
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.fallback.dealloc(ptr, layout)
+        let tier = self.tier(&layout);
+        let size_class = match tier {
+            Tier::Page(size_class) => size_class,
+            Tier::Large => {
+                stats::record(tier, layout.size(), false);
+                return large::dealloc(ptr, &layout)
+            }
+            Tier::Fallback => {
+                stats::record(tier, layout.size(), false);
+                return self.fallback.dealloc(ptr, layout)
+            }
+        };
+        let page = PageRef::of(ptr, size_class);
+
+        let freed = LOCAL.try_with(|local| {
+            local.stats.record(tier, layout.size(), false);
+            match local.pages[size_class].take() {
+                Some(mut owned) if *owned == page => {
+                    owned.free(ptr);
+                    local.pages[size_class].set(Some(owned));
+                    true
+                }
+                owned => {
+                    local.pages[size_class].set(owned);
+                    // Counted before the free, which may hand the page back.
+                    let head = page.head();
+                    if head.owner.load(Ordering::Relaxed) != local.stats.thread_id() {
+                        head.foreign_frees.fetch_add(1, Ordering::Relaxed);
+                        stats::count(Some(&local.stats), |counts| counts.remote_frees_sent += 1);
+                    }
+                    false
+                }
+            }
+        });
+
+        if freed.is_err() {
+            stats::record(tier, layout.size(), false);
+        }
+        if freed != Ok(true) {
+            page.free_remote(ptr);
+        }
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        if layout.size() > MAX_ALLOC_SIZE {
-            self.fallback.alloc_zeroed(layout)
-        } else {
-            let result = self.alloc(layout);
-            if result.is_null() { return result }
-            ptr::write_bytes(result, 0, layout.size());
-            result
+        let tier = self.tier(&layout);
+        let result = match tier {
+            Tier::Page(size_class) => {
+                let (slot, zeroed) = match self.alloc_slot(size_class, layout.size()) {
+                    Some(slot) => slot,
+                    None => return ptr::null_mut(),
+                };
+                if !zeroed {
+                    ptr::write_bytes(slot.as_ptr(), 0, layout.size());
+                }
+                return slot.as_ptr()
+            }
+            Tier::Large => large::alloc(&layout),
+            Tier::Fallback if self.config.fallback => self.fallback.alloc_zeroed(layout),
+            Tier::Fallback => ptr::null_mut(),
+        };
+
+        if !result.is_null() {
+            stats::record(tier, layout.size(), true);
         }
+        result
     }
 
     unsafe fn realloc(&self, prev: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        if layout.size() > MAX_ALLOC_SIZE {
-            self.fallback.realloc(prev, layout, new_size)
-        } else if get_slot_size(layout.size()) == get_slot_size(new_size) {
-            prev
-        } else {
-            self.dealloc(prev, layout);
-            self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()))
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        let tier = self.tier(&layout);
+        let resized = match (tier, self.tier(&new_layout)) {
+            (Tier::Fallback, Tier::Fallback) => Some(self.fallback.realloc(prev, layout, new_size)),
+            (Tier::Large, Tier::Large) => Some(large::realloc(prev, &layout, new_size)),
+            (Tier::Page(size_class), Tier::Page(new_size_class)) if size_class == new_size_class => Some(prev),
+            _ => None,
+        };
+        if let Some(result) = resized {
+            if !result.is_null() {
+                stats::record_resize(tier, layout.size(), new_size);
+            }
+            return result
         }
+
+        let result = self.alloc(new_layout);
+        if result.is_null() { return result }
+
+        ptr::copy_nonoverlapping(prev, result, layout.size().min(new_size));
+        self.dealloc(prev, layout);
+        result
     }
 }
 
-fn get_slot_size(size: usize) -> usize {
-    size / UNIT_SIZE + if size % UNIT_SIZE == 0 {0} else {1}
+/// Returns the size class whose slots fit `size` bytes aligned to `align`,
+/// or `None` if the request should go to the fallback allocator.
+///
+/// Slots are placed at multiples of the slot size from the page start, so
+/// rounding the size up to the alignment makes every slot of the class aligned.
+const fn get_size_class(size: usize, align: usize) -> Option<usize> {
+    if size > MAX_ALLOC_SIZE || align > PAGE_SIZE {
+        return None
+    }
+
+    let size = if size > align { size.next_multiple_of(align) } else { align };
+    if size > MAX_ALLOC_SIZE {
+        return None
+    }
+    if size <= MAX_LOOKUP_SIZE {
+        return Some(SIZE_CLASSES[size.div_ceil(UNIT_SIZE)] as usize)
+    }
+
+    Some(size_class_of(size))
+}
+
+/// Computes the size class of `size` bytes, which `SIZE_CLASSES` caches for
+/// small sizes.
+const fn size_class_of(size: usize) -> usize {
+    if size <= LINEAR_SIZE {
+        return size.saturating_sub(1) / UNIT_SIZE
+    }
+
+    // `size` lies in `(2^k, 2^(k+1)]`, which is split into classes `step` apart.
+    let k = (size - 1).ilog2();
+    let step = k - CLASSES_PER_DOUBLING.ilog2();
+    let within = ((size - 1) >> step) - CLASSES_PER_DOUBLING;
+    LINEAR_SLOTS + (k - LINEAR_SIZE.ilog2()) as usize * CLASSES_PER_DOUBLING + within
+}
+
+fn alloc_small(
+    page: Option<Page>,
+    size_class: usize,
+    config: &Config,
+    stats: Option<&stats::Shard>,
+) -> Option<((NonNull<u8>, bool), Page)> {
+    let mut page = match page {
+        Some(page) => page,
+        None => Page::acquire(size_class as u8, config, stats)?,
+    };
+
+    loop {
+        if let Some(slot) = page.alloc(stats) { return Some((slot, page)) }
+        page.release();
+        page = Page::acquire(size_class as u8, config, stats)?;
+    }
+}
+
+/// Takes the slots other threads freed into released pages of every size
+/// class, off the threads which adopt them.
+fn reclaim_orphans() {
+    for orphans in &ORPHANS {
+        lock(orphans).reclaim();
+    }
+}
+
+const fn size_classes() -> [u8; MAX_LOOKUP_SIZE / UNIT_SIZE + 1] {
+    let mut classes = [0; MAX_LOOKUP_SIZE / UNIT_SIZE + 1];
+    let mut units = 1;
+    while units < classes.len() {
+        classes[units] = size_class_of(units * UNIT_SIZE) as u8;
+        units += 1;
+    }
+    classes
+}
+
+const fn slot_sizes() -> [u32; SIZE_CLASS_COUNT] {
+    let mut sizes = [0; SIZE_CLASS_COUNT];
+    let mut size_class = 0;
+    while size_class < LINEAR_SLOTS {
+        sizes[size_class] = ((size_class + 1) * UNIT_SIZE) as u32;
+        size_class += 1;
+    }
+    while size_class < SIZE_CLASS_COUNT {
+        let geometric = size_class - LINEAR_SLOTS;
+        let base = LINEAR_SIZE << (geometric / CLASSES_PER_DOUBLING);
+        let step = base / CLASSES_PER_DOUBLING;
+        sizes[size_class] = (base + (geometric % CLASSES_PER_DOUBLING + 1) * step) as u32;
+        size_class += 1;
+    }
+    sizes
+}
+
+const fn page_sizes() -> [usize; SIZE_CLASS_COUNT] {
+    let mut sizes = [0; SIZE_CLASS_COUNT];
+    let mut size_class = 0;
+    while size_class < SIZE_CLASS_COUNT {
+        let mut size = PAGE_SIZE;
+        while (size - HEADERS_SIZE) / (SLOT_SIZES[size_class] as usize) < MIN_SLOTS_PER_PAGE {
+            size *= 2;
+        }
+        assert!(size <= segment::MAX_PAGE_SIZE);
+        sizes[size_class] = size;
+        size_class += 1;
+    }
+    sizes
+}
+
+const fn first_medium_class() -> usize {
+    let mut size_class = 0;
+    while PAGE_SIZES[size_class] == PAGE_SIZE {
+        size_class += 1;
+    }
+    size_class
+}
+
+fn remote_count(word: u64) -> u16 {
+    (word >> REMOTE_COUNT_SHIFT) as u16
+}
+
+fn remote_next(current: u64, count: u16, index: u16) -> u64 {
+    let flags = (current & !REMOTE_FIELDS_MASK).wrapping_add(REMOTE_TAG);
+    flags | (count as u64) << REMOTE_COUNT_SHIFT | index as u64
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Call {
+        Alloc(usize),
+        Dealloc(usize),
+        Realloc(usize, usize),
+    }
+
+    /// Fallback which records the size of every request reaching it.
+    #[derive(Debug, Default)]
+    struct Recorder {
+        calls: Mutex<Vec<Call>>,
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<Call> {
+            mem::take(&mut *lock(&self.calls))
+        }
+    }
+
+    unsafe impl GlobalAlloc for Recorder {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            lock(&self.calls).push(Call::Alloc(layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            lock(&self.calls).push(Call::Dealloc(layout.size()));
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            lock(&self.calls).push(Call::Realloc(layout.size(), new_size));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    fn slots_per_page(size_class: usize) -> usize {
+        (PAGE_SIZES[size_class] - HEADERS_SIZE) / SLOT_SIZES[size_class] as usize
+    }
+
+    #[test]
+    fn sizes_above_max_small_size_reach_fallback() {
+        let balloc = Balloc::with_fallback(Recorder::default(), Config::new().max_small_size(256));
+
+        unsafe {
+            let ptr = balloc.alloc(layout(257));
+            assert!(!ptr.is_null());
+            balloc.dealloc(ptr, layout(257));
+        }
+        assert_eq!(balloc.fallback().take(), [Call::Alloc(257), Call::Dealloc(257)]);
+    }
+
+    #[test]
+    fn pages_and_large_mappings_skip_fallback() {
+        let balloc = Balloc::with_fallback(Recorder::default(), Config::new().max_small_size(256));
+
+        for size in [0, 8, 256, LARGE_SIZE, 4 << 20] {
+            unsafe {
+                let ptr = balloc.alloc(layout(size));
+                assert!(!ptr.is_null());
+                balloc.dealloc(ptr, layout(size));
+            }
+        }
+        assert_eq!(balloc.fallback().take(), []);
+    }
+
+    #[test]
+    fn realloc_between_fallback_sizes_reaches_fallback() {
+        let balloc = Balloc::with_fallback(Recorder::default(), Config::new().max_small_size(256));
+
+        unsafe {
+            let ptr = balloc.alloc(layout(512));
+            let ptr = balloc.realloc(ptr, layout(512), 1024);
+            assert!(!ptr.is_null());
+            balloc.dealloc(ptr, layout(1024));
+        }
+        assert_eq!(balloc.fallback().take(), [Call::Alloc(512), Call::Realloc(512, 1024), Call::Dealloc(1024)]);
+    }
+
+    #[test]
+    fn disabled_fallback_returns_null() {
+        let config = Config::new().max_small_size(256).fallback(false);
+        let balloc = Balloc::with_fallback(Recorder::default(), config);
+
+        unsafe {
+            assert!(balloc.alloc(layout(512)).is_null());
+            assert!(balloc.alloc_zeroed(layout(512)).is_null());
+        }
+        assert_eq!(balloc.fallback().take(), []);
+    }
+
+    /// Reallocates a filled allocation of `size` bytes to `new_size` bytes
+    /// and checks the bytes both sizes have survive.
+    fn check_realloc(size: usize, new_size: usize) {
+        let balloc = Balloc::new();
+
+        unsafe {
+            let ptr = balloc.alloc(layout(size));
+            assert!(!ptr.is_null());
+            for i in 0..size {
+                *ptr.add(i) = (i % 251) as u8;
+            }
+
+            let ptr = balloc.realloc(ptr, layout(size), new_size);
+            assert!(!ptr.is_null());
+            for i in 0..size.min(new_size) {
+                assert_eq!(*ptr.add(i), (i % 251) as u8, "byte {} of {} moved to {}", i, size, new_size);
+            }
+            balloc.dealloc(ptr, layout(new_size));
+        }
+    }
+
+    fn tier(size: usize) -> Tier {
+        Balloc::new().tier(&layout(size))
+    }
+
+    #[test]
+    fn realloc_between_size_classes_copies() {
+        assert!(matches!((tier(24), tier(1200)), (Tier::Page(a), Tier::Page(b)) if a != b));
+        check_realloc(24, 1200);
+        check_realloc(1200, 24);
+    }
+
+    #[test]
+    fn realloc_from_a_slot_to_a_mapping_copies() {
+        assert!(matches!((tier(1500), tier(2 << 20)), (Tier::Page(_), Tier::Large)));
+        check_realloc(1500, 2 << 20);
+    }
+
+    #[test]
+    fn realloc_from_a_mapping_to_a_slot_copies() {
+        assert!(matches!((tier(2 << 20), tier(1700)), (Tier::Large, Tier::Page(_))));
+        check_realloc(2 << 20, 1700);
+    }
+
+    #[test]
+    fn realloc_between_mappings_keeps_contents() {
+        assert_eq!((tier(2 << 20), tier(3 << 20)), (Tier::Large, Tier::Large));
+        check_realloc(2 << 20, 3 << 20);
+        check_realloc(3 << 20, (1 << 20) + 1);
+    }
+
+    #[test]
+    fn zero_background_interval_is_rejected() {
+        let err = Balloc::new().start_background_thread(Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!background::is_running());
+    }
+
+    #[test]
+    fn segment_size_is_rounded_into_range() {
+        assert_eq!(Config::new().segment_size, 4 << 20);
+        assert_eq!(Config::new().segment_size(0).segment_size, 2 << 20);
+        assert_eq!(Config::new().segment_size(3 << 20).segment_size, 4 << 20);
+        assert_eq!(Config::new().segment_size(16 << 20).segment_size, 16 << 20);
+        assert_eq!(Config::new().segment_size(usize::MAX).segment_size, 32 << 20);
+    }
+
+    // Tests of pages and thread stats use size classes no other test does,
+    // so no other test thread adopts their pages.
+
+    #[test]
+    fn slots_freed_by_other_threads_are_reused() {
+        const PRODUCERS: usize = 4;
+        const ROUNDS: usize = 200;
+        const BATCH: usize = 64;
+        let balloc = Balloc::new();
+
+        std::thread::scope(|scope| {
+            for producer in 0..PRODUCERS {
+                let (sender, receiver) = std::sync::mpsc::sync_channel::<(u8, Vec<usize>)>(1);
+                let balloc = &balloc;
+
+                scope.spawn(move || {
+                    for (byte, ptrs) in receiver {
+                        for ptr in ptrs {
+                            let slot = unsafe { std::slice::from_raw_parts(ptr as *const u8, 224) };
+                            assert!(slot.iter().all(|&b| b == byte));
+                            unsafe { balloc.dealloc(ptr as *mut u8, layout(224)) };
+                        }
+                    }
+                });
+                scope.spawn(move || {
+                    let mut seen = std::collections::HashSet::new();
+                    for round in 0..ROUNDS {
+                        let byte = (producer * ROUNDS + round) as u8;
+                        let ptrs = (0..BATCH).map(|_| {
+                            let ptr = unsafe { balloc.alloc(layout(224)) };
+                            assert!(!ptr.is_null());
+                            unsafe { ptr.write_bytes(byte, 224) };
+                            seen.insert(ptr as usize);
+                            ptr as usize
+                        }).collect();
+                        sender.send((byte, ptrs)).unwrap();
+                    }
+
+                    // Only a few batches are in flight at once, so slots
+                    // come back through the remote free stacks.
+                    assert!(seen.len() < ROUNDS * BATCH / 10, "{} distinct slots", seen.len());
+                    assert!(thread_stats().remote_frees_received > 0);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn owner_takes_back_remote_frees_once_its_list_is_empty() {
+        let balloc = Balloc::new();
+        let slots = slots_per_page(get_size_class(320, 8).unwrap());
+
+        // Fills the thread's page, which stays its current page.
+        let ptrs = (0..slots).map(|_| unsafe { balloc.alloc(layout(320)) } as usize).collect::<Vec<_>>();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for &ptr in &ptrs {
+                    unsafe { balloc.dealloc(ptr as *mut u8, layout(320)) };
+                }
+            });
+        });
+
+        let before = thread_stats();
+        let again = (0..slots).map(|_| unsafe { balloc.alloc(layout(320)) } as usize).collect::<Vec<_>>();
+        let mut expected = ptrs.clone();
+        let mut got = again.clone();
+        expected.sort_unstable();
+        got.sort_unstable();
+        assert_eq!(got, expected);
+        assert_eq!(thread_stats().pages_created, before.pages_created);
+        assert_eq!(thread_stats().remote_frees_received - before.remote_frees_received, slots);
+
+        for ptr in again {
+            unsafe { balloc.dealloc(ptr as *mut u8, layout(320)) };
+        }
+    }
+
+
+    #[test]
+    fn released_pages_are_adopted_before_new_ones() {
+        let balloc = Balloc::new();
+        let size_class = get_size_class(384, 8).unwrap();
+        let slots = slots_per_page(size_class);
+
+        // The thread exits with its page full, which keeps the page out of
+        // the orphans until the first free into it.
+        let ptrs = std::thread::scope(|scope| {
+            scope.spawn(|| (0..slots).map(|_| unsafe { balloc.alloc(layout(384)) } as usize).collect::<Vec<_>>())
+                .join().unwrap()
+        });
+        unsafe { balloc.dealloc(ptrs[0] as *mut u8, layout(384)) };
+
+        let (ptr, stats) = std::thread::scope(|scope| {
+            scope.spawn(|| (unsafe { balloc.alloc(layout(384)) } as usize, thread_stats())).join().unwrap()
+        });
+        assert_eq!(ptr, ptrs[0]);
+        assert_eq!((stats.pages_adopted, stats.pages_created), (1, 0));
+        assert_eq!(balloc.stats().size_classes[size_class].pages, 1);
+
+        // Full again, so the last free has to take it out of the orphans.
+        for ptr in ptrs {
+            unsafe { balloc.dealloc(ptr as *mut u8, layout(384)) };
+        }
+        assert_eq!(balloc.stats().size_classes[size_class].pages, 0);
+    }
+
+    #[test]
+    fn last_free_into_a_released_page_unmaps_it() {
+        let balloc = Balloc::new();
+        let size_class = get_size_class(448, 8).unwrap();
+
+        let ptrs = std::thread::scope(|scope| {
+            scope.spawn(|| (0..3).map(|_| unsafe { balloc.alloc(layout(448)) } as usize).collect::<Vec<_>>())
+                .join().unwrap()
+        });
+        for ptr in ptrs {
+            assert_eq!(balloc.stats().size_classes[size_class].pages, 1);
+            unsafe { balloc.dealloc(ptr as *mut u8, layout(448)) };
+        }
+        assert_eq!(balloc.stats().size_classes[size_class].pages, 0);
+    }
+
+    #[test]
+    fn exiting_threads_release_their_pages() {
+        let balloc = Balloc::new();
+        let size_class = get_size_class(512, 8).unwrap();
+
+        // Every thread leaves a slot in use, which the next ones adopt the
+        // pages of instead of mapping new ones.
+        let kept = (0..100).map(|_| {
+            std::thread::scope(|scope| {
+                scope.spawn(|| unsafe {
+                    let ptrs = (0..50).map(|_| balloc.alloc(layout(512))).collect::<Vec<_>>();
+                    for &ptr in &ptrs[1..] {
+                        balloc.dealloc(ptr, layout(512));
+                    }
+                    ptrs[0] as usize
+                }).join().unwrap()
+            })
+        }).collect::<Vec<_>>();
+
+        let pages = balloc.stats().size_classes[size_class].pages;
+        assert!(pages <= kept.len() / slots_per_page(size_class) + 2, "{} pages", pages);
+        for ptr in kept {
+            unsafe { balloc.dealloc(ptr as *mut u8, layout(512)) };
+        }
+        assert_eq!(balloc.stats().size_classes[size_class].pages, 0);
+    }
+
+    #[test]
+    fn threads_allocate_after_their_thread_local_is_gone() {
+        static BALLOC: Balloc = Balloc::new();
+        static LOCAL_GONE: AtomicBool = AtomicBool::new(false);
+        static ROUND_TRIP: AtomicBool = AtomicBool::new(false);
+
+        /// Destroyed after `LOCAL`, as it's registered before it.
+        struct Late;
+
+        impl Drop for Late {
+            fn drop(&mut self) {
+                LOCAL_GONE.store(LOCAL.try_with(|_| ()).is_err(), Ordering::Relaxed);
+                unsafe {
+                    let ptr = BALLOC.alloc(layout(640));
+                    if ptr.is_null() { return }
+                    ptr.write_bytes(7, 640);
+                    let filled = std::slice::from_raw_parts(ptr, 640).iter().all(|&byte| byte == 7);
+                    BALLOC.dealloc(ptr, layout(640));
+                    ROUND_TRIP.store(filled, Ordering::Relaxed);
+                }
+            }
+        }
+
+        thread_local! {
+            static LATE: Late = const { Late };
+        }
+
+        std::thread::spawn(|| unsafe {
+            LATE.with(|_| ());
+            let ptr = BALLOC.alloc(layout(640));
+            BALLOC.dealloc(ptr, layout(640));
+        }).join().unwrap();
+
+        assert!(LOCAL_GONE.load(Ordering::Relaxed));
+        assert!(ROUND_TRIP.load(Ordering::Relaxed));
+        assert_eq!(BALLOC.stats().size_classes[get_size_class(640, 8).unwrap()].pages, 0);
+    }
+
+    #[test]
+    fn reclaimed_slots_go_to_the_adopting_thread() {
+        let balloc = Balloc::new();
+        let size_class = get_size_class(768, 8).unwrap();
+
+        let ptrs = std::thread::scope(|scope| {
+            scope.spawn(|| (0..4).map(|_| unsafe { balloc.alloc(layout(768)) } as usize).collect::<Vec<_>>())
+                .join().unwrap()
+        });
+        for &ptr in &ptrs[..2] {
+            unsafe { balloc.dealloc(ptr as *mut u8, layout(768)) };
+        }
+
+        lock(&ORPHANS[size_class]).reclaim();
+        let page = PageRef::of(ptrs[0] as *mut u8, size_class);
+        let next_free = page.head().next_free.load(Ordering::Relaxed);
+        assert_eq!((next_free as u16, remote_count(next_free)), (U16_MAX, 2));
+
+        let (mut again, stats) = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let again = (0..2).map(|_| unsafe { balloc.alloc(layout(768)) } as usize).collect::<Vec<_>>();
+                (again, thread_stats())
+            }).join().unwrap()
+        });
+        again.sort_unstable();
+        let mut freed = ptrs[..2].to_vec();
+        freed.sort_unstable();
+        assert_eq!(again, freed);
+        assert_eq!((stats.pages_adopted, stats.pages_created), (1, 0));
+
+        for ptr in again.into_iter().chain(ptrs[2..].iter().copied()) {
+            unsafe { balloc.dealloc(ptr as *mut u8, layout(768)) };
+        }
+        assert_eq!(balloc.stats().size_classes[size_class].pages, 0);
+    }
+
+    #[test]
+    fn own_pages_are_not_remote() {
+        let balloc = Balloc::new();
+        let before = thread_stats();
+
+        unsafe {
+            let ptrs = (0..10_000).map(|_| balloc.alloc(layout(96))).collect::<Vec<_>>();
+            // Leaves every page released with free slots, then adopts them.
+            for &ptr in ptrs.iter().step_by(2) {
+                balloc.dealloc(ptr, layout(96));
+            }
+            let again = (0..5_000).map(|_| balloc.alloc(layout(96))).collect::<Vec<_>>();
+            for ptr in ptrs.into_iter().skip(1).step_by(2).chain(again) {
+                balloc.dealloc(ptr, layout(96));
+            }
+        }
+
+        let stats = thread_stats();
+        assert_eq!(stats.allocations - before.allocations, 15_000);
+        assert_eq!(stats.frees - before.frees, 15_000);
+        assert_eq!(stats.remote_frees_sent, before.remote_frees_sent);
+        assert_eq!(stats.remote_frees_received, before.remote_frees_received);
+        assert_eq!(stats.pages_adopted, before.pages_adopted);
+    }
+
+    #[test]
+    fn frees_by_other_threads_are_remote() {
+        let balloc = Balloc::new();
+        let before = thread_stats();
+        let ptrs = (0..1000).map(|_| unsafe { balloc.alloc(layout(160)) } as usize).collect::<Vec<_>>();
+
+        let sent = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for &ptr in &ptrs {
+                    unsafe { balloc.dealloc(ptr as *mut u8, layout(160)) };
+                }
+                thread_stats()
+            }).join().unwrap()
+        });
+        assert_eq!(sent.frees, 1000);
+        assert_eq!(sent.remote_frees_sent, 1000);
+
+        // Draining the current page takes back the frees into it.
+        unsafe {
+            let ptrs = (0..1000).map(|_| balloc.alloc(layout(160))).collect::<Vec<_>>();
+            for ptr in ptrs {
+                balloc.dealloc(ptr, layout(160));
+            }
+        }
+        let received = thread_stats().remote_frees_received - before.remote_frees_received;
+        assert!(received > 0 && received <= 1000, "{} frees received", received);
+    }
 }
@@ -0,0 +1,147 @@
+//! Raw virtual memory management on top of the Linux system calls.
+//!
+//! Nothing here allocates or panics, so it's safe to call from inside the
+//! global allocator. Failures are reported as `None` or `false`.
+
+// The flags below take the values most architectures share, which others
+// such as mips and powerpc don't, and other systems differ more still. The
+// `mmap` offset is only 64 bits wide on 64-bit targets.
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64"))))]
+compile_error!("balloc only supports Linux on x86_64, aarch64 and riscv64");
+
+use std::ffi::{c_long, c_void};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const PROT_NONE: i32 = 0;
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_FIXED: i32 = 0x10;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_NORESERVE: i32 = 0x4000;
+const MAP_HUGETLB: i32 = 0x40000;
+
+const MREMAP_MAYMOVE: i32 = 1;
+
+const MADV_DONTNEED: i32 = 4;
+const MADV_FREE: i32 = 8;
+const MADV_HUGEPAGE: i32 = 14;
+
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+const SC_PAGESIZE: i32 = 30;
+const DEFAULT_PAGE_SIZE: usize = 4096;
+
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mremap(addr: *mut c_void, old_len: usize, new_len: usize, flags: i32, ...) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn madvise(addr: *mut c_void, len: usize, advice: i32) -> i32;
+    fn sysconf(name: i32) -> c_long;
+}
+
+/// Granularity of the OS' virtual memory. Ranges passed to the functions
+/// below must be aligned to it.
+pub fn page_size() -> usize {
+    let size = PAGE_SIZE.load(Ordering::Relaxed);
+    if size != 0 {
+        return size
+    }
+
+    let size = match unsafe { sysconf(SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => DEFAULT_PAGE_SIZE,
+    };
+    PAGE_SIZE.store(size, Ordering::Relaxed);
+    size
+}
+
+/// Reserves `size` bytes of address space without backing memory.
+/// The range must be committed before it's accessed.
+pub fn reserve(size: usize) -> Option<NonNull<u8>> {
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE;
+    let start = unsafe { mmap(ptr::null_mut(), size, PROT_NONE, flags, -1, 0) };
+
+    if start == MAP_FAILED {
+        return None
+    }
+    NonNull::new(start as *mut u8)
+}
+
+/// Maps `size` bytes of readable and writable memory, which reads as zero.
+pub fn map(size: usize) -> Option<NonNull<u8>> {
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let start = unsafe { mmap(ptr::null_mut(), size, PROT_READ | PROT_WRITE, flags, -1, 0) };
+
+    if start == MAP_FAILED {
+        return None
+    }
+    NonNull::new(start as *mut u8)
+}
+
+/// Resizes a range obtained from `map`, moving it if it can't be resized in
+/// place. The kernel moves the memory by remapping it instead of copying.
+/// On failure the range is left untouched.
+pub unsafe fn remap(start: NonNull<u8>, old_size: usize, new_size: usize) -> Option<NonNull<u8>> {
+    let start = mremap(start.as_ptr() as *mut c_void, old_size, new_size, MREMAP_MAYMOVE);
+
+    if start == MAP_FAILED {
+        return None
+    }
+    NonNull::new(start as *mut u8)
+}
+
+/// Makes a reserved range readable and writable. Fresh memory reads as zero.
+pub unsafe fn commit(start: NonNull<u8>, size: usize) -> bool {
+    mprotect(start.as_ptr() as *mut c_void, size, PROT_READ | PROT_WRITE) == 0
+}
+
+/// Commits a reserved range with memory from the explicit huge page pool.
+/// The range must be aligned to the huge page size. On failure the range is
+/// left reserved, so it can still be committed normally.
+pub unsafe fn commit_huge(start: NonNull<u8>, size: usize) -> bool {
+    let addr = start.as_ptr() as *mut c_void;
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED;
+
+    if mmap(addr, size, PROT_READ | PROT_WRITE, flags | MAP_HUGETLB, -1, 0) == addr {
+        return true
+    }
+    mmap(addr, size, PROT_NONE, flags | MAP_NORESERVE, -1, 0);
+    false
+}
+
+/// Asks the kernel to back a committed range with transparent huge pages.
+/// Returns false if they are unavailable.
+pub unsafe fn advise_huge(start: NonNull<u8>, size: usize) -> bool {
+    madvise(start.as_ptr() as *mut c_void, size, MADV_HUGEPAGE) == 0
+}
+
+/// Returns the memory behind a committed range to the OS. The range stays
+/// committed and reads as zero afterwards.
+pub unsafe fn purge(start: NonNull<u8>, size: usize) -> bool {
+    madvise(start.as_ptr() as *mut c_void, size, MADV_DONTNEED) == 0
+}
+
+/// Lets the OS take back the memory behind a committed range when it runs
+/// short of it. Until then the range keeps its contents. Returns false if
+/// the kernel doesn't support it.
+pub unsafe fn purge_lazy(start: NonNull<u8>, size: usize) -> bool {
+    madvise(start.as_ptr() as *mut c_void, size, MADV_FREE) == 0
+}
+
+/// Returns the memory behind a committed range to the OS, keeping the
+/// address space reserved.
+pub unsafe fn decommit(start: NonNull<u8>, size: usize) -> bool {
+    let start = start.as_ptr() as *mut c_void;
+    madvise(start, size, MADV_DONTNEED) == 0 && mprotect(start, size, PROT_NONE) == 0
+}
+
+/// Unmaps a range obtained from `reserve` or `map`.
+pub unsafe fn release(start: NonNull<u8>, size: usize) -> bool {
+    munmap(start.as_ptr() as *mut c_void, size) == 0
+}
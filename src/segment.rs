@@ -0,0 +1,681 @@
+//! Segments are large aligned chunks of memory which pages are carved from,
+//! so the OS is asked for memory once per segment instead of once per page.
+
+use std::mem;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{background, lock, os, Config, HugePages, Purge, PAGE_SIZE};
+
+/// Segment size unless configured otherwise.
+pub const SEGMENT_SIZE: usize = 4 << 20;
+/// Smallest segment size, which fits four pages of the largest size.
+pub const MIN_SEGMENT_SIZE: usize = MAX_PAGE_SIZE * 4;
+/// Largest segment size, whose bitmaps still fit the header page.
+pub const MAX_SEGMENT_SIZE: usize = 32 << 20;
+const BITMAP_LEN: usize = MAX_SEGMENT_SIZE / PAGE_SIZE / 64;
+
+/// Number of empty segments kept mapped to absorb allocation bursts.
+/// Segments emptied beyond this go back to the OS immediately.
+const MAX_EMPTY_SEGMENTS: usize = 1;
+
+/// Number of steps the decay window is split into. Free pages left unpurged
+/// are forgotten one step at a time.
+const DECAY_EPOCHS: usize = 16;
+
+/// Size of the huge pages segments may be backed with. Purging a huge page
+/// only in part would split it, so such segments are purged in whole ones.
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Largest page `alloc_page` hands out.
+pub const MAX_PAGE_SIZE: usize = 512 << 10;
+
+const _: () = assert!(mem::size_of::<Segment>() <= PAGE_SIZE);
+const _: () = assert!(MIN_SEGMENT_SIZE >= HUGE_PAGE_SIZE);
+const _: () = assert!(MIN_SEGMENT_SIZE <= SEGMENT_SIZE && SEGMENT_SIZE <= MAX_SEGMENT_SIZE);
+
+static SEGMENTS: Mutex<Segments> = Mutex::new(Segments {
+    first: None,
+    empty: 0,
+    heap: None,
+    decay: Decay { epoch: None, backlog: [0; DECAY_EPOCHS], unpurged: 0 },
+});
+
+/// Size of every segment, fixed by the config of the first allocator to
+/// map one or reserve the heap. Zero until then.
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+
+static HEAP_RESERVED: AtomicBool = AtomicBool::new(false);
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+
+static HUGE_PAGE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ADVISED_HUGE_PAGE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static MAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PURGED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+struct Segments {
+    /// Segments with at least one free page, though maybe no free run of
+    /// the size asked for.
+    first: Option<NonNull<Segment>>,
+    empty: usize,
+    heap: Option<Heap>,
+    decay: Decay,
+}
+
+/// Paces purging so the number of free pages left unpurged shrinks smoothly.
+/// Pages freed within the decay window are kept in proportion to how much
+/// of the window is left for them, and purged once past it.
+#[derive(Debug)]
+struct Decay {
+    /// Start of the current epoch, once a page was left unpurged.
+    epoch: Option<Instant>,
+    /// Pages left unpurged during each of the last epochs, latest first.
+    backlog: [usize; DECAY_EPOCHS],
+    /// Free pages not purged yet across all segments.
+    unpurged: usize,
+}
+
+/// Address range reserved up front. Once it exists every segment is carved
+/// from it, and released segments are decommitted and kept for reuse.
+#[derive(Debug)]
+struct Heap {
+    next: usize,
+    end: usize,
+    /// Released segments, linked through their headers. Only the first OS
+    /// page of them stays committed.
+    free: Option<NonNull<Segment>>,
+}
+
+/// Header of a segment, stored in its first page. Every other page is
+/// handed out to `Page::new`, alone or as part of an aligned run.
+#[derive(Debug)]
+struct Segment {
+    free: [u64; BITMAP_LEN],
+    /// Pages handed out since the segment was committed. The others still
+    /// read as zero.
+    dirty: [u64; BITMAP_LEN],
+    /// Free pages whose memory wasn't purged yet.
+    unpurged: [u64; BITMAP_LEN],
+    used: usize,
+    /// What backs the segment: explicit huge pages, ordinary pages advised
+    /// to become transparent huge pages, or ordinary pages.
+    huge_pages: HugePages,
+    /// How pages are purged once freed, taken from the config of the
+    /// allocator which mapped the segment.
+    purge: Purge,
+    /// Whether freed pages are left for the decay to purge instead of being
+    /// purged right away.
+    decay: bool,
+    prev: Option<NonNull<Segment>>,
+    next: Option<NonNull<Segment>>,
+}
+
+unsafe impl Send for Segments {}
+
+impl Segments {
+    fn push(&mut self, mut segment: NonNull<Segment>) {
+        unsafe {
+            let header = segment.as_mut();
+            header.prev = None;
+            header.next = self.first;
+
+            if let Some(mut first) = self.first {
+                first.as_mut().prev = Some(segment);
+            }
+        }
+        self.first = Some(segment);
+    }
+
+    fn remove(&mut self, mut segment: NonNull<Segment>) {
+        unsafe {
+            let header = segment.as_mut();
+            let prev = header.prev.take();
+            let next = header.next.take();
+
+            match prev {
+                Some(mut prev) => prev.as_mut().next = next,
+                None => self.first = next,
+            }
+            if let Some(mut next) = next {
+                next.as_mut().prev = prev;
+            }
+        }
+    }
+
+    /// Purges up to `pages` free pages which were left unpurged.
+    fn purge(&mut self, mut pages: usize) {
+        let mut cursor = self.first;
+
+        while let Some(mut segment) = cursor {
+            if pages == 0 {
+                break
+            }
+            let header = unsafe { segment.as_mut() };
+            let purged = unsafe { header.purge(pages) };
+            self.decay.unpurged -= purged;
+            pages = pages.saturating_sub(purged);
+            cursor = header.next;
+        }
+    }
+
+    fn purge_decayed(&mut self, window: Duration) {
+        let due = self.decay.tick(window);
+        if due != 0 {
+            self.purge(due);
+        }
+    }
+
+    /// Removes an empty segment and hands it back to the OS.
+    unsafe fn unmap(&mut self, segment: NonNull<Segment>) {
+        self.remove(segment);
+        self.decay.unpurged -= count_bits(&segment.as_ref().unpurged, 0, pages_per_segment());
+        Segment::unmap(segment, self.heap.as_mut());
+    }
+}
+
+impl Decay {
+    fn push(&mut self, pages: usize) {
+        self.epoch.get_or_insert_with(Instant::now);
+        self.backlog[0] += pages;
+        self.unpurged += pages;
+    }
+
+    /// Moves the epochs along and returns how many unpurged pages are due
+    /// for purging, decaying them over `window`.
+    fn tick(&mut self, window: Duration) -> usize {
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => return 0,
+        };
+        let epoch_len = window / DECAY_EPOCHS as u32;
+        if epoch_len.is_zero() {
+            return self.unpurged
+        }
+
+        let now = Instant::now();
+        let elapsed = (now.saturating_duration_since(epoch).as_nanos() / epoch_len.as_nanos()) as usize;
+        if elapsed == 0 {
+            return 0
+        }
+        if elapsed < DECAY_EPOCHS {
+            self.backlog.copy_within(..DECAY_EPOCHS - elapsed, elapsed);
+            self.backlog[..elapsed].fill(0);
+            self.epoch = Some(epoch + epoch_len * elapsed as u32);
+        } else {
+            self.backlog = [0; DECAY_EPOCHS];
+            self.epoch = Some(now);
+        }
+
+        let kept = self.backlog.iter().enumerate()
+            .map(|(age, &pages)| pages * (DECAY_EPOCHS - age) / DECAY_EPOCHS)
+            .sum::<usize>();
+        self.unpurged.saturating_sub(kept)
+    }
+
+    fn reset(&mut self) {
+        self.epoch = None;
+        self.backlog = [0; DECAY_EPOCHS];
+    }
+}
+
+impl Segment {
+    fn new(heap: Option<&mut Heap>, config: &Config) -> Option<NonNull<Segment>> {
+        let size = segment_size();
+        if !size.is_multiple_of(os::page_size()) {
+            return None
+        }
+
+        let (segment, huge_pages) = match heap {
+            Some(heap) => Segment::from_heap(heap, config.huge_pages)?,
+            None => Segment::map(config.huge_pages)?,
+        };
+        if let Some(bytes) = huge_page_counter(huge_pages) {
+            bytes.fetch_add(size, Ordering::Relaxed);
+        }
+        MAPPED_BYTES.fetch_add(size, Ordering::Relaxed);
+
+        let mut free = [0; BITMAP_LEN];
+        set_bits(&mut free, 1, size / PAGE_SIZE - 1, true);
+        // Released heap segments keep their first OS page, which may span
+        // more than the header page.
+        let mut dirty = [0; BITMAP_LEN];
+        for page in 0..header_size() / PAGE_SIZE {
+            dirty[page / 64] |= 1 << (page % 64);
+        }
+        let segment = segment.cast::<Segment>();
+        unsafe {
+            ptr::write(segment.as_ptr(), Segment {
+                free,
+                dirty,
+                unpurged: [0; BITMAP_LEN],
+                used: 0,
+                huge_pages,
+                purge: config.purge,
+                // Huge pages are only purged once free as a whole, which
+                // takes the decay's bookkeeping.
+                decay: config.purge != Purge::Off && (huge_pages != HugePages::Off || !config.purge_decay.is_zero()),
+                prev: None,
+                next: None,
+            });
+        }
+        Some(segment)
+    }
+
+    fn map(huge_pages: HugePages) -> Option<(NonNull<u8>, HugePages)> {
+        let size = segment_size();
+        let reserved = os::reserve(size * 2)?;
+        let reserved_start = reserved.as_ptr() as usize;
+        let start = (reserved_start + size - 1) & !(size - 1);
+        let end = start + size;
+
+        unsafe {
+            if start > reserved_start {
+                os::release(reserved, start - reserved_start);
+            }
+            let reserved_end = reserved_start + size * 2;
+            if reserved_end > end {
+                os::release(NonNull::new_unchecked(end as *mut u8), reserved_end - end);
+            }
+
+            let segment = NonNull::new_unchecked(start as *mut u8);
+            if huge_pages == HugePages::Explicit && os::commit_huge(segment, size) {
+                return Some((segment, HugePages::Explicit))
+            }
+            if !os::commit(segment, size) {
+                os::release(segment, size);
+                return None
+            }
+            Some((segment, advise_huge(segment, huge_pages)))
+        }
+    }
+
+    fn from_heap(heap: &mut Heap, huge_pages: HugePages) -> Option<(NonNull<u8>, HugePages)> {
+        let size = segment_size();
+        unsafe {
+            if let Some(segment) = heap.free {
+                let header_size = header_size();
+                let rest = NonNull::new_unchecked(segment.as_ptr().cast::<u8>().add(header_size));
+                if !os::commit(rest, size - header_size) {
+                    return None
+                }
+                heap.free = segment.as_ref().next;
+                let segment = segment.cast();
+                return Some((segment, advise_huge(segment, huge_pages)))
+            }
+
+            if heap.next == heap.end {
+                return None
+            }
+            let segment = NonNull::new_unchecked(heap.next as *mut u8);
+            if !os::commit(segment, size) {
+                return None
+            }
+            heap.next += size;
+            Some((segment, advise_huge(segment, huge_pages)))
+        }
+    }
+
+    unsafe fn unmap(mut segment: NonNull<Segment>, heap: Option<&mut Heap>) {
+        let size = segment_size();
+        if let Some(bytes) = huge_page_counter(segment.as_ref().huge_pages) {
+            bytes.fetch_sub(size, Ordering::Relaxed);
+        }
+        MAPPED_BYTES.fetch_sub(size, Ordering::Relaxed);
+
+        match heap {
+            Some(heap) if heap_contains(segment.as_ptr().cast()) => {
+                let header_size = header_size();
+                let rest = NonNull::new_unchecked(segment.as_ptr().cast::<u8>().add(header_size));
+                os::decommit(rest, size - header_size);
+                segment.as_mut().next = heap.free;
+                heap.free = Some(segment);
+            }
+            _ => {
+                os::release(segment.cast(), size);
+            }
+        }
+    }
+
+    fn of(page: NonNull<u8>) -> NonNull<Segment> {
+        let start = page.as_ptr() as usize & !(segment_size() - 1);
+        unsafe { NonNull::new_unchecked(start as *mut Segment) }
+    }
+
+    fn is_full(&self) -> bool {
+        self.used == pages_per_segment() - 1
+    }
+
+    /// Number of pages purged together: whole OS pages, or whole huge pages
+    /// if the segment may be backed by them.
+    fn purge_group(&self) -> usize {
+        let size = match self.huge_pages {
+            HugePages::Off => os::page_size(),
+            HugePages::Transparent | HugePages::Explicit => os::page_size().max(HUGE_PAGE_SIZE),
+        };
+        (size / PAGE_SIZE).max(1)
+    }
+
+    /// Takes `pages` free pages aligned to their count, so a run can be
+    /// found from any address in it by masking. `pages` is a power of two.
+    /// Also tells whether the run still reads as zero.
+    fn take_run(&mut self, pages: usize) -> Option<(usize, bool)> {
+        let len = pages_per_segment() / 64;
+        let index = if pages < 64 {
+            let mask = (1 << pages) - 1;
+            let word = self.free[..len].iter().position(|&word| run_in_word(word, pages, mask).is_some())?;
+            word * 64 + run_in_word(self.free[word], pages, mask)?
+        } else {
+            let words = pages / 64;
+            let word = (0..len).step_by(words)
+                .find(|&word| self.free[word..word + words].iter().all(|&word| word == !0))?;
+            word * 64
+        };
+
+        let zeroed = count_bits(&self.dirty, index, pages) == 0;
+        set_bits(&mut self.dirty, index, pages, true);
+
+        set_bits(&mut self.free, index, pages, false);
+        self.used += pages;
+        Some((index, zeroed))
+    }
+
+    /// Frees a run, which reads as zero again if `zeroed`.
+    fn put_run(&mut self, index: usize, pages: usize, zeroed: bool) {
+        set_bits(&mut self.free, index, pages, true);
+        self.used -= pages;
+
+        if zeroed {
+            set_bits(&mut self.dirty, index, pages, false);
+        }
+    }
+
+    /// Purges up to `limit` free pages left unpurged, in runs of whole
+    /// purge groups, and returns how many it purged. A group which doesn't
+    /// fit in what's left of `limit` is left for later.
+    unsafe fn purge(&mut self, limit: usize) -> usize {
+        let group = self.purge_group();
+        let pages = pages_per_segment();
+        let mut purged = 0;
+        let mut page = 0;
+
+        while page < pages && purged + group <= limit {
+            if self.unpurged[page / 64] == 0 {
+                page = ((page / 64 + 1) * 64).next_multiple_of(group);
+                continue
+            }
+
+            let start = page;
+            while page < pages && purged + page - start + group <= limit
+                && count_bits(&self.unpurged, page, group) == group
+            {
+                page += group;
+            }
+            if page == start {
+                page += group;
+                continue
+            }
+
+            let run = NonNull::new_unchecked((self as *mut Segment).cast::<u8>().add(start * PAGE_SIZE));
+            if purge(self.purge, run, (page - start) * PAGE_SIZE) {
+                set_bits(&mut self.dirty, start, page - start, false);
+            }
+            set_bits(&mut self.unpurged, start, page - start, false);
+            purged += page - start;
+        }
+        purged
+    }
+}
+
+fn set_bits(bitmap: &mut [u64; BITMAP_LEN], index: usize, len: usize, value: bool) {
+    for bit in index..index + len {
+        if value {
+            bitmap[bit / 64] |= 1 << (bit % 64);
+        } else {
+            bitmap[bit / 64] &= !(1 << (bit % 64));
+        }
+    }
+}
+
+fn count_bits(bitmap: &[u64; BITMAP_LEN], index: usize, len: usize) -> usize {
+    (index..index + len).filter(|&bit| bitmap[bit / 64] & 1 << (bit % 64) != 0).count()
+}
+
+/// Offset of the first free aligned run of `pages` pages within a bitmap word.
+fn run_in_word(word: u64, pages: usize, mask: u64) -> Option<usize> {
+    (0..64).step_by(pages).find(|&bit| (word >> bit) & mask == mask)
+}
+
+/// Returns the memory of a page about to be freed to the OS as configured,
+/// telling whether it reads as zero afterwards. Segments using huge pages
+/// leave their pages to the decay instead.
+///
+/// It's called without the lock, so it only reads header fields which never
+/// change after the segment is set up.
+unsafe fn purge_page(segment: NonNull<Segment>, page: NonNull<u8>, size: usize) -> bool {
+    let mode = ptr::read(ptr::addr_of!((*segment.as_ptr()).purge));
+    if !size.is_multiple_of(os::page_size()) {
+        return false
+    }
+    purge(mode, page, size)
+}
+
+/// Purges a free range, telling whether it reads as zero afterwards.
+unsafe fn purge(mode: Purge, start: NonNull<u8>, size: usize) -> bool {
+    let (purged, zeroed) = match mode {
+        Purge::Off => (false, false),
+        Purge::Free if os::purge_lazy(start, size) => (true, false),
+        Purge::Free | Purge::DontNeed => {
+            let zeroed = os::purge(start, size);
+            (zeroed, zeroed)
+        }
+    };
+
+    if purged {
+        PURGED_BYTES.fetch_add(size, Ordering::Relaxed);
+    }
+    zeroed
+}
+
+/// Hints a freshly committed segment to use transparent huge pages if asked.
+/// Explicit huge pages fall back to this when the pool is exhausted. Whether
+/// the kernel follows the hint is up to it.
+unsafe fn advise_huge(segment: NonNull<u8>, huge_pages: HugePages) -> HugePages {
+    if huge_pages != HugePages::Off && os::advise_huge(segment, segment_size()) {
+        return HugePages::Transparent
+    }
+    HugePages::Off
+}
+
+fn huge_page_counter(huge_pages: HugePages) -> Option<&'static AtomicUsize> {
+    match huge_pages {
+        HugePages::Off => None,
+        HugePages::Transparent => Some(&ADVISED_HUGE_PAGE_BYTES),
+        HugePages::Explicit => Some(&HUGE_PAGE_BYTES),
+    }
+}
+
+/// Part of a released heap segment which stays committed: the header page,
+/// rounded up to whole OS pages.
+fn header_size() -> usize {
+    PAGE_SIZE.next_multiple_of(os::page_size())
+}
+
+/// Fixes the size of every segment to the configured one, unless it was
+/// fixed before, and returns it.
+fn fix_segment_size(config: &Config) -> usize {
+    match SIZE.compare_exchange(0, config.segment_size, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => config.segment_size,
+        Err(size) => size,
+    }
+}
+
+/// Size of every segment, once one was mapped or the heap reserved.
+fn segment_size() -> usize {
+    SIZE.load(Ordering::Relaxed)
+}
+
+fn pages_per_segment() -> usize {
+    segment_size() / PAGE_SIZE
+}
+
+/// Hands out a committed page of `size` bytes, aligned to its size, and
+/// whether it still reads as zero. `size` is a power of two from `PAGE_SIZE`
+/// up to an eighth of a segment.
+pub fn alloc_page(size: usize, config: &Config) -> Option<(NonNull<u8>, bool)> {
+    let pages = size / PAGE_SIZE;
+    let mut segments = lock(&SEGMENTS);
+    let mut cursor = segments.first;
+
+    let (segment, index, zeroed) = loop {
+        let mut segment = match cursor {
+            Some(segment) => segment,
+            None => {
+                fix_segment_size(config);
+                let segment = Segment::new(segments.heap.as_mut(), config)?;
+                segments.push(segment);
+                segments.empty += 1;
+                segment
+            }
+        };
+
+        let header = unsafe { segment.as_mut() };
+        let used = header.used;
+        if let Some((index, zeroed)) = header.take_run(pages) {
+            if used == 0 {
+                segments.empty -= 1;
+            }
+            break (segment, index, zeroed)
+        }
+        cursor = header.next;
+    };
+
+    let header = unsafe { &mut *segment.as_ptr() };
+    let reused = count_bits(&header.unpurged, index, pages);
+    set_bits(&mut header.unpurged, index, pages, false);
+    segments.decay.unpurged -= reused;
+    if header.is_full() {
+        segments.remove(segment);
+    }
+
+    if !background::is_running() {
+        segments.purge_decayed(config.purge_decay);
+    }
+
+    let page = segment.as_ptr() as usize + index * PAGE_SIZE;
+    Some((NonNull::new(page as *mut u8)?, zeroed))
+}
+
+/// Returns a page of `size` bytes obtained from `alloc_page`, releasing its
+/// segment to the OS once every page of it is free and enough empty segments
+/// are kept. The page is purged right away or left for the decay.
+pub unsafe fn free_page(page: NonNull<u8>, size: usize) {
+    let mut segment = Segment::of(page);
+    let index = (page.as_ptr() as usize - segment.as_ptr() as usize) / PAGE_SIZE;
+    let pages = size / PAGE_SIZE;
+    let decay = ptr::read(ptr::addr_of!((*segment.as_ptr()).decay));
+    let zeroed = !decay && purge_page(segment, page, size);
+
+    let mut segments = lock(&SEGMENTS);
+    let header = segment.as_mut();
+    if header.is_full() {
+        segments.push(segment);
+    }
+    header.put_run(index, pages, zeroed);
+    // Pages sharing a purge group with the header are never purged.
+    if decay && index >= header.purge_group() {
+        set_bits(&mut header.unpurged, index, pages, true);
+        segments.decay.push(pages);
+    }
+
+    if header.used == 0 {
+        if segments.empty < MAX_EMPTY_SEGMENTS {
+            segments.empty += 1;
+        } else {
+            segments.unmap(segment);
+        }
+    }
+}
+
+/// Purges the free pages left unpurged for longer than the decay keeps them.
+pub fn purge_decayed(window: Duration) {
+    lock(&SEGMENTS).purge_decayed(window);
+}
+
+/// Purges every free page left for the decay.
+pub fn purge_all() {
+    let mut segments = lock(&SEGMENTS);
+    let unpurged = segments.decay.unpurged;
+    segments.purge(unpurged);
+    segments.decay.reset();
+}
+
+/// Reserves the configured heap size of address space for all future
+/// segments, unless it was tried before. Without the reservation segments
+/// are mapped anywhere.
+pub fn reserve_heap(config: &Config) {
+    if HEAP_RESERVED.load(Ordering::Relaxed) {
+        return
+    }
+
+    let mut segments = lock(&SEGMENTS);
+    if HEAP_RESERVED.swap(true, Ordering::Relaxed) {
+        return
+    }
+
+    let segment_size = fix_segment_size(config);
+    let size = match config.heap_size.checked_next_multiple_of(segment_size) {
+        Some(size) if size <= usize::MAX - segment_size => size,
+        _ => return,
+    };
+    let reserved = match os::reserve(size + segment_size) {
+        Some(reserved) => reserved.as_ptr() as usize,
+        None => return,
+    };
+    let start = (reserved + segment_size - 1) & !(segment_size - 1);
+
+    segments.heap = Some(Heap {
+        next: start,
+        end: start + size,
+        free: None,
+    });
+    HEAP_END.store(start + size, Ordering::Relaxed);
+    HEAP_START.store(start, Ordering::Release);
+}
+
+/// Whether `ptr` lies within the reserved heap.
+pub fn heap_contains(ptr: *const u8) -> bool {
+    let start = HEAP_START.load(Ordering::Acquire);
+    start != 0 && (start..HEAP_END.load(Ordering::Relaxed)).contains(&(ptr as usize))
+}
+
+/// Bytes of segments mapped, whether purged or not.
+pub fn mapped_bytes() -> usize {
+    MAPPED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Number of segments mapped.
+pub fn segment_count() -> usize {
+    match segment_size() {
+        0 => 0,
+        size => mapped_bytes() / size,
+    }
+}
+
+/// Bytes of pages purged so far.
+pub fn purged_bytes() -> usize {
+    PURGED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Bytes of segments backed by explicit huge pages.
+pub fn huge_page_bytes() -> usize {
+    HUGE_PAGE_BYTES.load(Ordering::Relaxed)
+}
+
+/// Bytes of segments advised to use transparent huge pages.
+pub fn advised_huge_page_bytes() -> usize {
+    ADVISED_HUGE_PAGE_BYTES.load(Ordering::Relaxed)
+}
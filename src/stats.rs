@@ -0,0 +1,381 @@
+//! Counters behind `Balloc::stats`.
+//!
+//! Counters touched on every allocation are sharded per thread: each thread
+//! keeps its shard in its `Local` and only it writes to it, so bumping a
+//! counter is a plain load and store. `collect` sums the shards of running
+//! threads, which register on their first allocation, and the totals of
+//! exited ones. A slot may be freed by another thread than the one which
+//! allocated it, so single shards can wrap below zero and only their sum is
+//! meaningful. Counters of slow paths are plain atomics.
+//!
+//! The shard also keeps the thread's own counts behind `thread_stats`, which
+//! no other thread reads.
+
+use std::cell::Cell;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{
+    large, lock, segment, Tier, FIRST_MEDIUM_CLASS, HEADERS_SIZE, LOCAL, PAGE_SIZES, SIZE_CLASS_COUNT, SLOT_SIZES,
+};
+
+/// Shards of running threads.
+static THREADS: Mutex<Threads> = Mutex::new(Threads { first: None });
+/// Totals of exited threads, and counts of threads whose `Local` is gone.
+static EXITED: Shard = Shard::new();
+
+static NEXT_THREAD_ID: AtomicU32 = AtomicU32::new(1);
+
+static PAGES: [AtomicUsize; SIZE_CLASS_COUNT] = [const { AtomicUsize::new(0) }; SIZE_CLASS_COUNT];
+
+/// Snapshot of the allocations of every `Balloc` in the process, which
+/// share their pages. Counters are read while other threads keep going, so
+/// they may be slightly off from each other.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes requested by live allocations.
+    pub allocated: usize,
+    /// Bytes of slots, direct mappings and fallback allocations holding live
+    /// allocations.
+    pub active: usize,
+    /// Bytes mapped from the OS for segments and direct mappings. Purged
+    /// pages stay mapped.
+    pub mapped: usize,
+    /// Segments mapped for pages, each of the segment size of the first
+    /// allocator to map one.
+    pub segments: usize,
+    /// Bytes of freed pages purged so far.
+    pub purged: usize,
+    /// Bytes of segments backed by explicit huge pages.
+    pub huge_pages: usize,
+    /// Bytes of segments advised to use transparent huge pages.
+    pub advised_huge_pages: usize,
+    /// Every size class served from pages, smallest first.
+    pub size_classes: Vec<SizeClassStats>,
+    /// Requests served from single 4 KiB pages.
+    pub small: TierStats,
+    /// Requests served from pages spanning several 4 KiB pages.
+    pub medium: TierStats,
+    /// Requests mapped directly from the OS.
+    pub large: TierStats,
+    /// Requests sent to the fallback allocator.
+    pub fallback: TierStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub slot_size: usize,
+    pub page_size: usize,
+    /// Whether the class belongs to the medium tier.
+    pub medium: bool,
+    pub pages: usize,
+    pub live_slots: usize,
+    /// Slots of the pages not holding an allocation.
+    pub free_slots: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TierStats {
+    /// Live allocations.
+    pub allocations: usize,
+    /// Bytes requested by live allocations.
+    pub bytes: usize,
+}
+
+/// What the current thread did since it started, across every `Balloc`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThreadStats {
+    pub allocations: usize,
+    pub frees: usize,
+    /// Frees of slots in pages another thread allocates from, or last did.
+    pub remote_frees_sent: usize,
+    /// Slots freed by other threads into pages the thread allocates from,
+    /// counted as it takes them back. Frees into a page it released count
+    /// only if it adopts the page again before the background thread
+    /// reclaims them.
+    pub remote_frees_received: usize,
+    pub pages_created: usize,
+    /// Pages released by other threads which the thread took over.
+    pub pages_adopted: usize,
+}
+
+#[derive(Debug)]
+struct Threads {
+    first: Option<NonNull<Shard>>,
+}
+
+/// Counters of a thread. The links are only touched with `THREADS` locked.
+#[derive(Debug)]
+pub struct Shard {
+    allocated: AtomicUsize,
+    slots: [AtomicUsize; SIZE_CLASS_COUNT],
+    small_bytes: AtomicUsize,
+    medium_bytes: AtomicUsize,
+    large: AtomicUsize,
+    large_bytes: AtomicUsize,
+    fallback: AtomicUsize,
+    fallback_bytes: AtomicUsize,
+    counts: Cell<ThreadStats>,
+    id: Cell<u32>,
+    registered: Cell<bool>,
+    prev: Cell<Option<NonNull<Shard>>>,
+    next: Cell<Option<NonNull<Shard>>>,
+}
+
+unsafe impl Send for Threads {}
+unsafe impl Sync for Shard {}
+
+impl Shard {
+    pub const fn new() -> Self {
+        Shard {
+            allocated: AtomicUsize::new(0),
+            slots: [const { AtomicUsize::new(0) }; SIZE_CLASS_COUNT],
+            small_bytes: AtomicUsize::new(0),
+            medium_bytes: AtomicUsize::new(0),
+            large: AtomicUsize::new(0),
+            large_bytes: AtomicUsize::new(0),
+            fallback: AtomicUsize::new(0),
+            fallback_bytes: AtomicUsize::new(0),
+            counts: Cell::new(ThreadStats {
+                allocations: 0,
+                frees: 0,
+                remote_frees_sent: 0,
+                remote_frees_received: 0,
+                pages_created: 0,
+                pages_adopted: 0,
+            }),
+            id: Cell::new(0),
+            registered: Cell::new(false),
+            prev: Cell::new(None),
+            next: Cell::new(None),
+        }
+    }
+
+    /// Id of the thread owning the shard, handed out on first use. Zero is
+    /// left for no thread.
+    pub fn thread_id(&self) -> u32 {
+        if self.id.get() == 0 {
+            self.id.set(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+        }
+        self.id.get()
+    }
+
+    /// Folds the counters into `EXITED` and unlinks the shard, as the
+    /// thread's `Local` goes away.
+    pub fn retire(&self) {
+        if !self.registered.get() {
+            return
+        }
+
+        let mut threads = lock(&THREADS);
+        let (prev, next) = (self.prev.take(), self.next.take());
+        match prev {
+            Some(prev) => unsafe { prev.as_ref() }.next.set(next),
+            None => threads.first = next,
+        }
+        if let Some(next) = next {
+            unsafe { next.as_ref() }.prev.set(prev);
+        }
+        self.registered.set(false);
+
+        self.zip(&EXITED, |counter, exited| {
+            exited.fetch_add(counter.load(Ordering::Relaxed), Ordering::Relaxed);
+        });
+    }
+
+    fn register(&self) {
+        let mut threads = lock(&THREADS);
+        let this = NonNull::from(self);
+
+        self.next.set(threads.first);
+        if let Some(first) = threads.first {
+            unsafe { first.as_ref() }.prev.set(Some(this));
+        }
+        threads.first = Some(this);
+        self.registered.set(true);
+    }
+
+    fn zip(&self, other: &Shard, mut f: impl FnMut(&AtomicUsize, &AtomicUsize)) {
+        f(&self.allocated, &other.allocated);
+        for (counter, other) in self.slots.iter().zip(&other.slots) {
+            f(counter, other);
+        }
+        f(&self.small_bytes, &other.small_bytes);
+        f(&self.medium_bytes, &other.medium_bytes);
+        f(&self.large, &other.large);
+        f(&self.large_bytes, &other.large_bytes);
+        f(&self.fallback, &other.fallback);
+        f(&self.fallback_bytes, &other.fallback_bytes);
+    }
+
+    /// Records an allocation of `size` bytes, or a free if `added` is false,
+    /// made by the thread owning the shard.
+    pub fn record(&self, tier: Tier, size: usize, added: bool) {
+        let (count, size) = delta(size, added);
+        self.add_own(tier, count, size);
+    }
+
+    fn add_own(&self, tier: Tier, count: usize, size: usize) {
+        if !self.registered.get() {
+            self.register();
+        }
+        self.add(tier, count, size, false);
+
+        // Frees add `usize::MAX`, which wraps to one less.
+        match count {
+            1 => count_in(self, |counts| counts.allocations += 1),
+            usize::MAX => count_in(self, |counts| counts.frees += 1),
+            _ => {}
+        }
+    }
+
+    /// Adds `count` allocations and `size` bytes to the counters of `tier`.
+    /// `shared` tells whether other threads may write to them too.
+    fn add(&self, tier: Tier, count: usize, size: usize, shared: bool) {
+        let (allocations, bytes) = match tier {
+            Tier::Page(size_class) if size_class < FIRST_MEDIUM_CLASS => (&self.slots[size_class], &self.small_bytes),
+            Tier::Page(size_class) => (&self.slots[size_class], &self.medium_bytes),
+            Tier::Large => (&self.large, &self.large_bytes),
+            Tier::Fallback => (&self.fallback, &self.fallback_bytes),
+        };
+
+        add(allocations, count, shared);
+        add(&self.allocated, size, shared);
+        add(bytes, size, shared);
+    }
+}
+
+fn add(counter: &AtomicUsize, value: usize, shared: bool) {
+    if shared {
+        counter.fetch_add(value, Ordering::Relaxed);
+    } else {
+        counter.store(counter.load(Ordering::Relaxed).wrapping_add(value), Ordering::Relaxed);
+    }
+}
+
+/// Records an allocation of `size` bytes, or a free if `added` is false,
+/// made by the current thread.
+pub fn record(tier: Tier, size: usize, added: bool) {
+    let (count, size) = delta(size, added);
+    record_with(tier, count, size);
+}
+
+/// Records an allocation resized in place from `size` to `new_size` bytes.
+pub fn record_resize(tier: Tier, size: usize, new_size: usize) {
+    record_with(tier, 0, new_size.wrapping_sub(size));
+}
+
+fn record_with(tier: Tier, count: usize, size: usize) {
+    if LOCAL.try_with(|local| local.stats.add_own(tier, count, size)).is_err() {
+        EXITED.add(tier, count, size, true);
+    }
+}
+
+fn delta(size: usize, added: bool) -> (usize, usize) {
+    if added { (1, size) } else { (1usize.wrapping_neg(), size.wrapping_neg()) }
+}
+
+/// Bumps the counts of the thread owning `shard`, if there's one.
+pub fn count(shard: Option<&Shard>, f: impl FnOnce(&mut ThreadStats)) {
+    if let Some(shard) = shard {
+        count_in(shard, f);
+    }
+}
+
+fn count_in(shard: &Shard, f: impl FnOnce(&mut ThreadStats)) {
+    let mut counts = shard.counts.get();
+    f(&mut counts);
+    shard.counts.set(counts);
+}
+
+/// Returns what the current thread allocated, freed and passed between
+/// threads since it started. Other threads' counters aren't touched, so
+/// it never blocks.
+pub fn thread_stats() -> ThreadStats {
+    LOCAL.try_with(|local| local.stats.counts.get()).unwrap_or_default()
+}
+
+/// Records a page of `size_class` created, or unmapped if `added` is false.
+pub fn record_page(size_class: usize, added: bool) {
+    if added {
+        PAGES[size_class].fetch_add(1, Ordering::Relaxed);
+    } else {
+        PAGES[size_class].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn collect() -> Stats {
+    let total = Shard::new();
+    let sum = |shard: &Shard| {
+        shard.zip(&total, |counter, total| add(total, counter.load(Ordering::Relaxed), false));
+    };
+
+    sum(&EXITED);
+    {
+        let threads = lock(&THREADS);
+        let mut cursor = threads.first;
+        while let Some(shard) = cursor {
+            let shard = unsafe { shard.as_ref() };
+            sum(shard);
+            cursor = shard.next.get();
+        }
+    }
+
+    let size_classes = (0..SIZE_CLASS_COUNT).map(|size_class| {
+        let slot_size = SLOT_SIZES[size_class] as usize;
+        let page_size = PAGE_SIZES[size_class];
+        let pages = PAGES[size_class].load(Ordering::Relaxed);
+        let live_slots = read(&total.slots[size_class]);
+        let slots = pages * ((page_size - HEADERS_SIZE) / slot_size);
+
+        SizeClassStats {
+            slot_size,
+            page_size,
+            medium: size_class >= FIRST_MEDIUM_CLASS,
+            pages,
+            live_slots,
+            free_slots: slots.saturating_sub(live_slots),
+        }
+    }).collect::<Vec<_>>();
+
+    let (small_classes, medium_classes) = size_classes.split_at(FIRST_MEDIUM_CLASS);
+    let small = TierStats {
+        allocations: small_classes.iter().map(|class| class.live_slots).sum(),
+        bytes: read(&total.small_bytes),
+    };
+    let medium = TierStats {
+        allocations: medium_classes.iter().map(|class| class.live_slots).sum(),
+        bytes: read(&total.medium_bytes),
+    };
+    let large = TierStats {
+        allocations: read(&total.large),
+        bytes: read(&total.large_bytes),
+    };
+    let fallback = TierStats {
+        allocations: read(&total.fallback),
+        bytes: read(&total.fallback_bytes),
+    };
+    let slot_bytes = size_classes.iter().map(|class| class.live_slots * class.slot_size).sum::<usize>();
+
+    Stats {
+        allocated: read(&total.allocated),
+        active: slot_bytes + large::mapped_bytes() + fallback.bytes,
+        mapped: segment::mapped_bytes() + large::mapped_bytes(),
+        segments: segment::segment_count(),
+        purged: segment::purged_bytes(),
+        huge_pages: segment::huge_page_bytes(),
+        advised_huge_pages: segment::advised_huge_page_bytes(),
+        size_classes,
+        small,
+        medium,
+        large,
+        fallback,
+    }
+}
+
+/// Reads a summed counter. Frees counted before the allocations they free
+/// can make it briefly negative, which reads as zero.
+fn read(counter: &AtomicUsize) -> usize {
+    (counter.load(Ordering::Relaxed) as isize).max(0) as usize
+}
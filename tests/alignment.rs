@@ -0,0 +1,46 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use balloc::Balloc;
+
+const PAGE_SIZE: usize = 4096;
+/// Slots taken for every size and alignment, so slots past the first of a
+/// page are checked too.
+const SLOTS: usize = 10;
+
+#[test]
+fn slots_are_aligned_in_every_size_class() {
+    let balloc = Balloc::new();
+    let slot_sizes = balloc.stats().size_classes.iter().map(|class| class.slot_size).collect::<Vec<_>>();
+    let mut ptrs = Vec::new();
+
+    for &size in &slot_sizes {
+        for align in (0..=PAGE_SIZE.ilog2()).map(|shift| 1 << shift) {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            for _ in 0..SLOTS {
+                let ptr = unsafe { balloc.alloc(layout) };
+                assert!(!ptr.is_null());
+                assert!((ptr as usize).is_multiple_of(align), "{:p} for {} bytes aligned to {}", ptr, size, align);
+                ptrs.push((ptr, layout));
+            }
+        }
+    }
+
+    let stats = balloc.stats();
+    assert_eq!(stats.small.allocations + stats.medium.allocations, ptrs.len());
+    assert_eq!(stats.fallback.allocations, 0);
+
+    // Alignments beyond a page go to the fallback.
+    for align in [PAGE_SIZE * 2, PAGE_SIZE * 16] {
+        for size in [8, PAGE_SIZE] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = unsafe { balloc.alloc(layout) };
+            assert!(!ptr.is_null() && (ptr as usize).is_multiple_of(align));
+            ptrs.push((ptr, layout));
+        }
+    }
+    assert_eq!(balloc.stats().fallback.allocations, 4);
+
+    for (ptr, layout) in ptrs {
+        unsafe { balloc.dealloc(ptr, layout) };
+    }
+}
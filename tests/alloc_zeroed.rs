@@ -0,0 +1,32 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use balloc::Balloc;
+
+#[test]
+fn fresh_pages_are_zeroed() {
+    let balloc = Balloc::new();
+    let layout = Layout::from_size_align(512, 8).unwrap();
+
+    let ptrs = (0..60).map(|_| unsafe { balloc.alloc_zeroed(layout) }).collect::<Vec<_>>();
+    for ptr in ptrs {
+        assert!(unsafe { std::slice::from_raw_parts(ptr, layout.size()) }.iter().all(|&byte| byte == 0));
+        unsafe { balloc.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn reused_slots_are_zeroed() {
+    let balloc = Balloc::new();
+    let layout = Layout::from_size_align(100, 8).unwrap();
+
+    unsafe {
+        let ptr = balloc.alloc(layout);
+        ptr.write_bytes(0xff, layout.size());
+        balloc.dealloc(ptr, layout);
+
+        let again = balloc.alloc_zeroed(layout);
+        assert_eq!(again, ptr);
+        assert!(std::slice::from_raw_parts(again, layout.size()).iter().all(|&byte| byte == 0));
+        balloc.dealloc(again, layout);
+    }
+}
@@ -0,0 +1,13 @@
+mod common;
+
+use std::time::Duration;
+
+use balloc::{Balloc, Config, Purge};
+
+#[test]
+fn lazily_purged_pages_are_zeroed_on_reuse() {
+    // `MADV_FREE` leaves the contents in place until the kernel needs the
+    // memory, so they must be cleared anyway.
+    let balloc = Balloc::with_config(Config::new().purge(Purge::Free).purge_decay(Duration::ZERO));
+    common::check_reused_pages_are_zeroed(&balloc, 512, 60);
+}
@@ -0,0 +1,9 @@
+mod common;
+
+use balloc::{Balloc, Config, Purge};
+
+#[test]
+fn pages_kept_unpurged_are_zeroed_on_reuse() {
+    let balloc = Balloc::with_config(Config::new().purge(Purge::Off));
+    common::check_reused_pages_are_zeroed(&balloc, 512, 60);
+}
@@ -0,0 +1,98 @@
+//! Helpers for tests watching what happens to freed pages. Segments are
+//! shared by the whole process, so every purge mode is tested in its own
+//! test binary.
+
+#![allow(dead_code)]
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::fs;
+use std::thread;
+
+use balloc::Balloc;
+
+const SIZE: usize = 16 << 10;
+const COUNT: usize = 4096;
+/// One allocation out of this many is kept alive, so every segment keeps a
+/// page in use and stays mapped while the others are freed.
+const KEPT_EVERY: usize = 128;
+
+/// Resident set size of the process in OS pages, from `/proc/self/statm`.
+pub fn rss() -> usize {
+    let statm = fs::read_to_string("/proc/self/statm").unwrap();
+    statm.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+/// Allocations made by `churn`, with the RSS before and at their peak.
+pub struct Churn {
+    pub base: usize,
+    pub peak: usize,
+    kept: Vec<*mut u8>,
+}
+
+/// Allocates and writes to many slots spanning many pages, then frees all
+/// but a few of them.
+pub fn churn(balloc: &Balloc) -> Churn {
+    let layout = Layout::from_size_align(SIZE, 8).unwrap();
+    let mut kept = Vec::with_capacity(COUNT / KEPT_EVERY);
+    let mut freed = Vec::with_capacity(COUNT);
+    let base = rss();
+
+    for i in 0..COUNT {
+        let ptr = unsafe { balloc.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { ptr.write_bytes(1, SIZE) };
+        if i % KEPT_EVERY == 0 { kept.push(ptr) } else { freed.push(ptr) }
+    }
+
+    let peak = rss();
+    for ptr in freed {
+        unsafe { balloc.dealloc(ptr, layout) };
+    }
+    Churn { base, peak, kept }
+}
+
+impl Churn {
+    /// Resident pages gained since the allocations started, relative to
+    /// their peak.
+    pub fn retained(&self, rss: usize) -> f64 {
+        rss.saturating_sub(self.base) as f64 / (self.peak - self.base) as f64
+    }
+
+    pub fn free(self, balloc: &Balloc) {
+        let layout = Layout::from_size_align(SIZE, 8).unwrap();
+        for ptr in self.kept {
+            unsafe { balloc.dealloc(ptr, layout) };
+        }
+    }
+}
+
+/// Fills `count` slots of `size` bytes on a thread of its own, whose exit
+/// frees their pages, then takes the same slots back with `alloc_zeroed` on
+/// another thread and checks every byte reads as zero.
+pub fn check_reused_pages_are_zeroed(balloc: &Balloc, size: usize, count: usize) {
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let ptrs = thread::scope(|scope| {
+        scope.spawn(|| {
+            let ptrs = (0..count).map(|_| unsafe { balloc.alloc(layout) }).collect::<Vec<_>>();
+            for &ptr in &ptrs {
+                unsafe {
+                    ptr.write_bytes(0xff, size);
+                    balloc.dealloc(ptr, layout);
+                }
+            }
+            ptrs.into_iter().map(|ptr| ptr as usize).collect::<Vec<_>>()
+        }).join().unwrap()
+    });
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let again = (0..count).map(|_| unsafe { balloc.alloc_zeroed(layout) } as usize).collect::<Vec<_>>();
+            assert_eq!(again, ptrs, "the freed pages weren't reused");
+            for ptr in again {
+                let slot = unsafe { std::slice::from_raw_parts(ptr as *const u8, size) };
+                assert!(slot.iter().all(|&byte| byte == 0), "{:#x} isn't zeroed", ptr);
+                unsafe { balloc.dealloc(ptr as *mut u8, layout) };
+            }
+        });
+    });
+}
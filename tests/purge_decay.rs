@@ -0,0 +1,17 @@
+mod common;
+
+use std::time::Duration;
+
+use balloc::{Balloc, Config, Purge};
+
+#[test]
+fn zero_decay_purges_on_free() {
+    let config = Config::new().purge(Purge::DontNeed).purge_decay(Duration::ZERO);
+    let balloc = Balloc::with_config(config);
+    let churn = common::churn(&balloc);
+
+    let retained = churn.retained(common::rss());
+    assert!(retained < 0.5, "{:.0}% of the peak is still resident", retained * 100.0);
+
+    churn.free(&balloc);
+}
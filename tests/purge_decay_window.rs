@@ -0,0 +1,51 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::thread;
+use std::time::Duration;
+
+use balloc::{Balloc, Config, Purge};
+
+const DECAY: Duration = Duration::from_millis(3200);
+/// A sixteenth of the decay window, over which a sixteenth of the freed
+/// pages is purged.
+const EPOCH: Duration = Duration::from_millis(200);
+
+static BALLOC: Balloc = Balloc::with_config(Config::new().purge(Purge::DontNeed).purge_decay(DECAY));
+
+/// Fills and frees `pages` pages of 256 KiB on a thread of its own, whose
+/// exit hands the last of them back too.
+fn free_pages(pages: usize) -> usize {
+    let layout = Layout::from_size_align(16 << 10, 8).unwrap();
+    thread::spawn(move || {
+        let ptrs = (0..pages * 15).map(|_| unsafe { BALLOC.alloc(layout) }).collect::<Vec<_>>();
+        for ptr in ptrs {
+            unsafe {
+                ptr.write_bytes(1, layout.size());
+                BALLOC.dealloc(ptr, layout);
+            }
+        }
+    }).join().unwrap();
+    pages * (256 << 10)
+}
+
+/// Takes a new page, which moves the decay along.
+fn new_page(size: usize) {
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let ptr = unsafe { BALLOC.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { BALLOC.dealloc(ptr, layout) };
+}
+
+#[test]
+fn decay_purges_freed_pages_gradually() {
+    let freed = free_pages(10);
+    assert_eq!(BALLOC.stats().purged, 0);
+
+    thread::sleep(EPOCH + EPOCH / 2);
+    new_page(64);
+    let purged = BALLOC.stats().purged;
+    assert!(purged > 0 && purged < freed / 4, "{} of {} bytes purged after an epoch", purged, freed);
+
+    thread::sleep(DECAY);
+    new_page(128);
+    assert_eq!(BALLOC.stats().purged, freed);
+}
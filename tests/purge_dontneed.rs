@@ -0,0 +1,16 @@
+mod common;
+
+use balloc::{Balloc, Config, Purge};
+
+#[test]
+fn purge_now_returns_freed_pages() {
+    let balloc = Balloc::with_config(Config::new().purge(Purge::DontNeed));
+    let churn = common::churn(&balloc);
+
+    balloc.purge_now();
+    let retained = churn.retained(common::rss());
+    assert!(retained < 0.5, "{:.0}% of the peak is still resident", retained * 100.0);
+    assert!(balloc.stats().purged > 0);
+
+    churn.free(&balloc);
+}
@@ -0,0 +1,12 @@
+mod common;
+
+use balloc::{Balloc, Config, HugePages, Purge};
+
+#[test]
+fn huge_page_segments_are_purged() {
+    let balloc = Balloc::with_config(Config::new().purge(Purge::DontNeed).huge_pages(HugePages::Transparent));
+    common::churn(&balloc).free(&balloc);
+
+    balloc.purge_now();
+    assert!(balloc.stats().purged > 0);
+}
@@ -0,0 +1,44 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::thread;
+use std::time::Duration;
+
+use balloc::{Balloc, Config, HugePages, Purge};
+
+const DECAY: Duration = Duration::from_millis(3200);
+const EPOCH: Duration = Duration::from_millis(200);
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+static BALLOC: Balloc = Balloc::with_config(
+    Config::new().purge(Purge::DontNeed).huge_pages(HugePages::Transparent).purge_decay(DECAY),
+);
+
+/// Takes a new page, which moves the decay along.
+fn new_page(size: usize) {
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let ptr = unsafe { BALLOC.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { BALLOC.dealloc(ptr, layout) };
+}
+
+#[test]
+fn huge_pages_decay_in_whole_huge_pages() {
+    // Fills the first segment with the 15 pages of 256 KiB it fits, the
+    // last eight of which make up its second huge page, then frees them.
+    let layout = Layout::from_size_align(16 << 10, 8).unwrap();
+    thread::spawn(move || {
+        let ptrs = (0..15 * 15).map(|_| unsafe { BALLOC.alloc(layout) }).collect::<Vec<_>>();
+        for ptr in ptrs {
+            unsafe { BALLOC.dealloc(ptr, layout) };
+        }
+    }).join().unwrap();
+    assert!(BALLOC.stats().advised_huge_pages > 0, "transparent huge pages are unavailable");
+
+    // A sixteenth of the huge page is due, which isn't worth splitting it.
+    thread::sleep(EPOCH + EPOCH / 2);
+    new_page(64);
+    assert_eq!(BALLOC.stats().purged, 0);
+
+    thread::sleep(DECAY);
+    new_page(128);
+    assert_eq!(BALLOC.stats().purged, HUGE_PAGE_SIZE);
+}
@@ -0,0 +1,16 @@
+mod common;
+
+use balloc::{Balloc, Config, Purge};
+
+#[test]
+fn purge_off_keeps_freed_pages() {
+    let balloc = Balloc::with_config(Config::new().purge(Purge::Off));
+    let churn = common::churn(&balloc);
+
+    balloc.purge_now();
+    let retained = churn.retained(common::rss());
+    assert!(retained > 0.75, "only {:.0}% of the peak is still resident", retained * 100.0);
+    assert_eq!(balloc.stats().purged, 0);
+
+    churn.free(&balloc);
+}
@@ -0,0 +1,29 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use balloc::{Balloc, Config};
+
+const SEGMENT_SIZE: usize = 2 << 20;
+
+#[test]
+fn pages_come_from_segments_of_the_configured_size() {
+    let balloc = Balloc::with_config(Config::new().segment_size(SEGMENT_SIZE));
+    let mut ptrs = Vec::new();
+
+    // Mixes the smallest and the largest size class, whose pages are a
+    // quarter of such a segment.
+    for i in 0..2000 {
+        let layout = Layout::from_size_align(if i % 2 == 0 { 8 } else { 32 << 10 }, 8).unwrap();
+        let ptr = unsafe { balloc.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { ptr.write_bytes(i as u8, layout.size()) };
+        ptrs.push((ptr, layout));
+    }
+
+    let stats = balloc.stats();
+    assert!(stats.segments > 0 && stats.mapped == stats.segments * SEGMENT_SIZE);
+
+    for (i, &(ptr, layout)) in ptrs.iter().enumerate() {
+        assert!(unsafe { std::slice::from_raw_parts(ptr, layout.size()) }.iter().all(|&byte| byte == i as u8));
+        unsafe { balloc.dealloc(ptr, layout) };
+    }
+}
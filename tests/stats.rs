@@ -0,0 +1,35 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use balloc::{Balloc, TierStats};
+
+#[test]
+fn stats_break_allocations_down_by_tier() {
+    let balloc = Balloc::new();
+    let sizes = [(64, 100), (8 << 10, 10), (64 << 10, 2), (2 << 20, 1)];
+    let mut ptrs = Vec::new();
+
+    for &(size, count) in &sizes {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        for _ in 0..count {
+            ptrs.push((unsafe { balloc.alloc(layout) }, layout));
+        }
+    }
+
+    let stats = balloc.stats();
+    assert_eq!(stats.small, TierStats { allocations: 100, bytes: 100 * 64 });
+    assert_eq!(stats.medium, TierStats { allocations: 10, bytes: 10 * (8 << 10) });
+    assert_eq!(stats.fallback, TierStats { allocations: 2, bytes: 2 * (64 << 10) });
+    assert_eq!(stats.large, TierStats { allocations: 1, bytes: 2 << 20 });
+    assert_eq!(stats.allocated, 100 * 64 + 10 * (8 << 10) + 2 * (64 << 10) + (2 << 20));
+
+    let class = |size| stats.size_classes.iter().find(|class| class.slot_size >= size).unwrap();
+    assert!(!class(64).medium && class(64).live_slots == 100);
+    assert!(class(8 << 10).medium && class(8 << 10).live_slots == 10);
+
+    for (ptr, layout) in ptrs {
+        unsafe { balloc.dealloc(ptr, layout) };
+    }
+    let stats = balloc.stats();
+    assert_eq!(stats.allocated, 0);
+    assert_eq!(stats.small.allocations + stats.medium.allocations, 0);
+}